@@ -0,0 +1,54 @@
+use core::future::Future;
+use core::pin::pin;
+use core::task::{
+    Context,
+    Poll,
+    RawWaker,
+    RawWakerVTable,
+    Waker,
+};
+use block_device::BlockDevice;
+
+/// Device whose read AND write are async, every BlockDevice is also one
+///
+/// On-disk logic is written once against this trait, the blocking API runs it with block_on
+#[allow(async_fn_in_trait)]
+pub trait AsyncBlockDevice {
+    type Error;
+    async fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error>;
+    async fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error>;
+}
+
+impl<T> AsyncBlockDevice for T
+    where T: BlockDevice {
+    type Error = <T as BlockDevice>::Error;
+
+    async fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        BlockDevice::read(self, buf, address, number_of_blocks)
+    }
+
+    async fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        BlockDevice::write(self, buf, address, number_of_blocks)
+    }
+}
+
+const NOOP_WAKER: RawWakerVTable = RawWakerVTable::new(|_| noop_raw_waker(), |_| (), |_| (), |_| ());
+
+fn noop_raw_waker() -> RawWaker {
+    RawWaker::new(core::ptr::null(), &NOOP_WAKER)
+}
+
+/// Run future of the blocking API to the end
+///
+/// Futures over BlockDevice are ready at the first poll, nothing waits for a wake
+pub(crate) fn block_on<F>(future: F) -> F::Output
+    where F: Future {
+    let mut future = pin!(future);
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
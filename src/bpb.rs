@@ -1,3 +1,51 @@
+/// Define FAT Type, Determined By Count Of Clusters
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq)]
+pub enum FatType {
+    FAT12,
+    FAT16,
+    FAT32,
+}
+
+impl FatType {
+    /// Get FAT Type from count of data clusters, the way the spec does
+    pub(crate) fn from_cluster_count(count: u32) -> FatType {
+        if count < 4085 {
+            FatType::FAT12
+        } else if count < 65525 {
+            FatType::FAT16
+        } else {
+            FatType::FAT32
+        }
+    }
+
+    /// Check if the FAT entry value means end of chain, any value of the range does
+    pub(crate) fn is_end(&self, value: u32) -> bool {
+        match self {
+            FatType::FAT12 => value >= 0xFF8,
+            FatType::FAT16 => value >= 0xFFF8,
+            FatType::FAT32 => value >= 0x0FFFFFF8,
+        }
+    }
+
+    /// Get the clean shutdown bit of FAT[1], FAT12 has no such bit
+    pub(crate) fn clean_mask(&self) -> Option<u32> {
+        match self {
+            FatType::FAT12 => None,
+            FatType::FAT16 => Some(0x8000),
+            FatType::FAT32 => Some(0x08000000),
+        }
+    }
+
+    /// Check if the FAT entry value marks the cluster as bad
+    pub(crate) fn is_bad(&self, value: u32) -> bool {
+        match self {
+            FatType::FAT12 => value == 0xFF7,
+            FatType::FAT16 => value == 0xFFF7,
+            FatType::FAT32 => value == 0x0FFFFFF7,
+        }
+    }
+}
+
 /// Define BIOS Parameters
 #[derive(Debug, Copy, Clone)]
 pub struct BIOSParameterBlock {
@@ -5,30 +53,56 @@ pub struct BIOSParameterBlock {
     pub(crate) sector_per_cluster: u8,
     pub(crate) reserved_sector: u16,
     pub(crate) num_fat: u8,
+    pub(crate) root_entry_count: u16,
     pub(crate) total_sector: u32,
     pub(crate) sector_per_fat: u32,
     pub(crate) root_cluster: u32,
+    pub(crate) fs_info: u32,
     pub(crate) id: u32,
     pub(crate) volume_label: [u8; 11],
-    pub(crate) file_system: [u8; 8],
+    pub(crate) fat_type: FatType,
 }
 
 impl BIOSParameterBlock {
-    /// Get the first sector offset bytes of the cluster from the cluster number
-    pub(crate) fn offset(&self, cluster: u32) -> usize {
-        ((self.reserved_sector as usize)
-            + (self.num_fat as usize) * (self.sector_per_fat as usize)
-            + (cluster as usize - 2) * (self.sector_per_cluster as usize))
-            * (self.byte_per_sector as usize)
+    /// Get the first sector of the cluster from the cluster number
+    pub(crate) fn sector(&self, cluster: u32) -> u32 {
+        self.first_data_sector() + (cluster - 2) * (self.sector_per_cluster as u32)
     }
 
-    /// Get FAT1 Offset
-    pub(crate) fn fat1(&self) -> usize {
-        (self.reserved_sector as usize) * (self.byte_per_sector as usize)
+    /// Get the first sector of FAT1
+    pub(crate) fn fat1(&self) -> u32 {
+        self.reserved_sector as u32
+    }
+
+    /// Get the first sector of the fixed root dir region (FAT12/FAT16)
+    pub(crate) fn root_dir_sector(&self) -> u32 {
+        (self.reserved_sector as u32) + (self.num_fat as u32) * self.sector_per_fat
+    }
+
+    /// Get sectors of the fixed root dir region, 0 on FAT32
+    pub(crate) fn root_dir_sectors(&self) -> usize {
+        let bps = self.byte_per_sector as usize;
+        ((self.root_entry_count as usize) * 32).div_ceil(bps)
+    }
+
+    /// Get the first sector of the data region
+    pub(crate) fn first_data_sector(&self) -> u32 {
+        self.root_dir_sector() + self.root_dir_sectors() as u32
+    }
+
+    /// Get count of clusters in the data region
+    pub(crate) fn cluster_count(&self) -> u32 {
+        let data_sector = self.total_sector - self.first_data_sector();
+        data_sector / (self.sector_per_cluster as u32)
+    }
+
+    /// Get the max valid cluster number
+    pub(crate) fn max_cluster(&self) -> u32 {
+        self.cluster_count() + 1
     }
 
     /// Get sector_per_cluster_usize as usize value
     pub(crate) fn sector_per_cluster_usize(&self) -> usize {
         self.sector_per_cluster as usize
     }
-}
\ No newline at end of file
+}
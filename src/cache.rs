@@ -0,0 +1,225 @@
+use core::cell::{
+    Cell,
+    RefCell,
+};
+use core::fmt::{
+    Debug,
+    Formatter,
+    Result,
+};
+use block_device::BlockDevice;
+use crate::BUFFER_SIZE;
+use crate::tool::sector_address;
+
+#[derive(Copy, Clone)]
+struct Line {
+    address: Option<usize>,
+    dirty: bool,
+    used: u64,
+    /// when the line became dirty, lines are written back in this order
+    order: u64,
+    data: [u8; BUFFER_SIZE],
+}
+
+impl Line {
+    const EMPTY: Line = Line {
+        address: None,
+        dirty: false,
+        used: 0,
+        order: 0,
+        data: [0; BUFFER_SIZE],
+    };
+}
+
+/// Write-Back Cache Of N Sectors, Least Recently Used One Is Evicted
+///
+/// Single-sector reads AND writes go through it, so FAT, dir AND data share it.
+/// Multi-sector transfers go to the device directly and keep cached sectors up to date.
+///
+/// The device always gets the writes in the order they were made, like without cache:
+/// dirty sectors are written back in the order they became dirty, a sector written again
+/// after a newer one became dirty is written back first, so is every dirty sector
+/// before a multi-sector write
+pub struct SectorCache<const N: usize> {
+    lines: RefCell<[Line; N]>,
+    clock: Cell<u64>,
+}
+
+impl<const N: usize> SectorCache<N> {
+    pub const fn new() -> Self {
+        Self {
+            lines: RefCell::new([Line::EMPTY; N]),
+            clock: Cell::new(0),
+        }
+    }
+
+    /// Get Count Of Sectors Which Are Not Written Back
+    pub fn dirty_count(&self) -> usize {
+        self.lines.borrow().iter().filter(|line| line.dirty).count()
+    }
+
+    fn tick(&self) -> u64 {
+        let clock = self.clock.get() + 1;
+        self.clock.set(clock);
+        clock
+    }
+
+    /// Get index of the line which holds address, evict one if missing
+    ///
+    /// Data of a new line is read from device only if read is true
+    fn line<T>(&self, lines: &mut [Line; N], device: &T, address: usize, read: bool) -> core::result::Result<usize, T::Error>
+        where T: BlockDevice {
+        let used = self.tick();
+        if let Some(index) = lines.iter().position(|line| line.address == Some(address)) {
+            lines[index].used = used;
+            return Ok(index);
+        }
+
+        let index = match lines.iter().position(|line| line.address.is_none()) {
+            Some(index) => index,
+            None => {
+                let (index, _) = lines.iter().enumerate().min_by_key(|(_, line)| line.used).unwrap();
+                // the lines which became dirty before it go to device first
+                if lines[index].dirty { Self::write_back(lines, device, lines[index].order)?; }
+                index
+            }
+        };
+
+        let line = &mut lines[index];
+        line.address = None;
+        line.dirty = false;
+        if read { device.read(&mut line.data, address, 1)?; }
+        line.address = Some(address);
+        line.used = used;
+        Ok(index)
+    }
+
+    /// Write Back Dirty Sectors To Device In Write Order, They Stay Cached
+    pub fn flush<T>(&self, device: &T) -> core::result::Result<(), T::Error>
+        where T: BlockDevice {
+        Self::write_back(&mut self.lines.borrow_mut(), device, u64::MAX)
+    }
+
+    /// Write back dirty lines in the order they became dirty, up to the one of order last
+    fn write_back<T>(lines: &mut [Line; N], device: &T, last: u64) -> core::result::Result<(), T::Error>
+        where T: BlockDevice {
+        while let Some(line) = lines.iter_mut()
+            .filter(|line| line.dirty && line.order <= last)
+            .min_by_key(|line| line.order) {
+            device.write(&line.data, line.address.unwrap(), 1)?;
+            line.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Write to the line, it keeps its order only if no line became dirty after it
+    fn write_line<T>(&self, lines: &mut [Line; N], device: &T, index: usize, buf: &[u8]) -> core::result::Result<(), T::Error>
+        where T: BlockDevice {
+        let line = &lines[index];
+        if line.dirty && lines.iter().any(|other| other.dirty && other.order > line.order) {
+            Self::write_back(lines, device, u64::MAX)?;
+        }
+
+        let line = &mut lines[index];
+        line.data.copy_from_slice(&buf[0..BUFFER_SIZE]);
+        if !line.dirty {
+            line.dirty = true;
+            line.order = self.tick();
+        }
+        Ok(())
+    }
+
+    /// Iterate cached lines in the range, with their offset in buffer
+    fn lines_in(lines: &mut [Line; N], address: usize, number_of_blocks: usize) -> impl Iterator<Item=(usize, &mut Line)> {
+        let step = sector_address(1);
+        let end = address + number_of_blocks * step;
+        lines.iter_mut().filter_map(move |line| match line.address {
+            Some(a) if a >= address && a < end => Some(((a - address) / step * BUFFER_SIZE, line)),
+            _ => None,
+        })
+    }
+}
+
+impl<const N: usize> Default for SectorCache<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Debug for SectorCache<N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_struct("SectorCache")
+            .field("capacity", &N)
+            .field("dirty", &self.dirty_count())
+            .finish()
+    }
+}
+
+/// Wrap BlockDevice With SectorCache, Every Copy Shares The Cache
+#[derive(Debug, Copy, Clone)]
+pub struct Cached<'a, T, const N: usize>
+    where T: BlockDevice {
+    device: T,
+    cache: &'a SectorCache<N>,
+}
+
+impl<'a, T, const N: usize> Cached<'a, T, N>
+    where T: BlockDevice {
+    pub fn new(device: T, cache: &'a SectorCache<N>) -> Self {
+        Self {
+            device,
+            cache,
+        }
+    }
+
+    /// Write Back Dirty Sectors To Device
+    pub fn flush(&self) -> core::result::Result<(), T::Error> {
+        self.cache.flush(&self.device)
+    }
+
+    /// Get The Wrapped Device, Flush First
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+}
+
+impl<'a, T, const N: usize> BlockDevice for Cached<'a, T, N>
+    where T: BlockDevice {
+    type Error = <T as BlockDevice>::Error;
+
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> core::result::Result<(), Self::Error> {
+        let mut lines = self.cache.lines.borrow_mut();
+        if number_of_blocks == 1 {
+            let index = self.cache.line(&mut lines, &self.device, address, true)?;
+            let len = core::cmp::min(buf.len(), BUFFER_SIZE);
+            buf[0..len].copy_from_slice(&lines[index].data[0..len]);
+            return Ok(());
+        }
+
+        // cached sectors may be newer than device
+        self.device.read(buf, address, number_of_blocks)?;
+        for (offset, line) in SectorCache::lines_in(&mut lines, address, number_of_blocks) {
+            if offset >= buf.len() { continue; }
+            let len = core::cmp::min(buf.len() - offset, BUFFER_SIZE);
+            buf[offset..offset + len].copy_from_slice(&line.data[0..len]);
+        }
+        Ok(())
+    }
+
+    fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> core::result::Result<(), Self::Error> {
+        let mut lines = self.cache.lines.borrow_mut();
+        if number_of_blocks == 1 {
+            let index = self.cache.line(&mut lines, &self.device, address, false)?;
+            return self.cache.write_line(&mut lines, &self.device, index, buf);
+        }
+
+        // the sectors written before go to device before these ones
+        SectorCache::write_back(&mut lines, &self.device, u64::MAX)?;
+        self.device.write(buf, address, number_of_blocks)?;
+        for (offset, line) in SectorCache::lines_in(&mut lines, address, number_of_blocks) {
+            line.data.copy_from_slice(&buf[offset..offset + BUFFER_SIZE]);
+            line.dirty = false;
+        }
+        Ok(())
+    }
+}
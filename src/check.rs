@@ -0,0 +1,597 @@
+use block_device::BlockDevice;
+use crate::async_device::block_on;
+use crate::BUFFER_SIZE;
+use crate::bpb::{
+    BIOSParameterBlock,
+    FatType,
+};
+use crate::dir::{
+    Dir,
+    DirError,
+    DirIter,
+    OpType,
+};
+use crate::directory_item::DirectoryItem;
+use crate::fat::FAT;
+use crate::tool::sector_address;
+use crate::volume::Volume;
+
+const END_OF_CHAIN: u32 = 0x0FFFFFFF;
+/// Dir in root for recovered lost chains
+const FOUND_DIR: &str = "found.000";
+/// Deepest level of dirs check() goes into, the root dir is level 1
+pub const MAX_DIR_DEPTH: usize = 64;
+
+/// Define CheckError
+#[derive(Debug, PartialOrd, PartialEq)]
+pub enum CheckError {
+    BitmapTooSmall,
+    ReadOnly,
+}
+
+/// Define What repair() Does With Lost Chains
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LostChains {
+    /// Mark clusters of lost chain as free
+    Free,
+    /// Save every lost chain as FILExxxx.CHK in FOUND.000 dir of root
+    Recover,
+}
+
+/// Define What repair() Does With Clusters Past The File Length
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Reserved {
+    /// Keep them, they are reserved by File::reserve OR create_file_with_size
+    Keep,
+    /// Free them, like 'fsck.fat -a' truncates the chain to the file length
+    Free,
+}
+
+/// Define Item Location, To Tell Which Item Has Problem
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Entry {
+    /// first cluster of the dir which contains the item, 0 for the fixed root dir region
+    pub dir_cluster: u32,
+    /// index of the item in the dir, '.' AND '..' included
+    pub index: usize,
+    /// short name of the item, like b"TEST    TXT"
+    pub name: [u8; 11],
+}
+
+/// Define Problem Found By Checker
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Problem {
+    /// Chain allocated in FAT, but no item uses it
+    LostChain { cluster: u32, length: u32 },
+    /// Chain runs into cluster which was already used, previous is 0 if it is the first cluster
+    CrossLinked { entry: Entry, previous: u32, cluster: u32 },
+    /// Chain has fewer clusters than the file length needs
+    SizeMismatch { entry: Entry, length: u32, clusters: u32 },
+    /// Chain has more clusters than the file length needs, File::reserve leaves them on purpose
+    ExtraClusters { entry: Entry, length: u32, clusters: u32 },
+    /// Chain points to invalid cluster number, previous is 0 if it is the first cluster
+    InvalidCluster { entry: Entry, previous: u32, cluster: u32 },
+    /// Checksum of long file name items doesn't match the short name item behind them
+    LfnChecksum { entry: Entry, count: usize },
+    /// Long file name items without the short name item, entry is the first one of them
+    OrphanLfn { entry: Entry, count: usize },
+    /// '.' OR '..' item of the dir is bad
+    BadDotItem { cluster: u32 },
+    /// Sector of FAT copy is different from FAT1
+    FatMismatch { fat: u8, sector: u32 },
+    /// Dir is deeper than MAX_DIR_DEPTH, it is not checked AND lost chains are not looked for
+    DirTooDeep { entry: Entry },
+}
+
+/// Get Bytes Of Bitmap Which check() Needs
+pub fn bitmap_size<T>(volume: &Volume<T>) -> usize
+    where T: BlockDevice,
+          <T as BlockDevice>::Error: core::fmt::Debug {
+    // two bits per cluster: used by item, pointed to by lost cluster
+    (volume.bpb.max_cluster() as usize + 1).div_ceil(8) * 2
+}
+
+/// Check Volume And Report Every Problem To Callback, Return Count Of Problems
+///
+/// Nothing is written to the device, bitmap is used instead of allocation.
+/// If it returns 0, flush() marks the volume clean even if it was dirty at mount
+pub fn check<T, F>(volume: &Volume<T>, bitmap: &mut [u8], report: F) -> Result<usize, CheckError>
+    where T: BlockDevice,
+          <T as BlockDevice>::Error: core::fmt::Debug,
+          F: FnMut(Problem) {
+    let mut report = report;
+    run(volume, bitmap, None, Reserved::Keep, |problem, _| report(problem))
+}
+
+/// Check Volume And Fix Problems Like 'fsck.fat -a', Return Count Of Problems
+///
+/// Callback gets every problem AND whether it was fixed.
+/// Cross-linked chains, invalid first clusters AND bad '.' items are only reported,
+/// so are clusters past the file length with Reserved::Keep
+pub fn repair<T, F>(volume: &Volume<T>, bitmap: &mut [u8], lost: LostChains, reserved: Reserved, report: F) -> Result<usize, CheckError>
+    where T: BlockDevice,
+          <T as BlockDevice>::Error: core::fmt::Debug,
+          F: FnMut(Problem, bool) {
+    if volume.is_read_only() { return Err(CheckError::ReadOnly); }
+    block_on(volume.mark_dirty());
+    run(volume, bitmap, Some(lost), reserved, report)
+}
+
+fn run<T, F>(volume: &Volume<T>, bitmap: &mut [u8], repair: Option<LostChains>, reserved: Reserved, report: F) -> Result<usize, CheckError>
+    where T: BlockDevice,
+          <T as BlockDevice>::Error: core::fmt::Debug,
+          F: FnMut(Problem, bool) {
+    let size = bitmap_size(volume);
+    if bitmap.len() < size { return Err(CheckError::BitmapTooSmall); }
+
+    let (used, pointed) = bitmap[0..size].split_at_mut(size / 2);
+    used.iter_mut().for_each(|b| *b = 0);
+    pointed.iter_mut().for_each(|b| *b = 0);
+
+    let mut checker = Checker {
+        volume,
+        device: &volume.device,
+        bpb: &volume.bpb,
+        fat: volume.fat(0),
+        used: Bitmap(used),
+        pointed: Bitmap(pointed),
+        repair,
+        reserved,
+        report,
+        count: 0,
+        complete: true,
+    };
+
+    let root_cluster = volume.bpb.root_cluster;
+    if root_cluster == 0 || checker.walk_chain(Entry::root(), root_cluster).is_some() {
+        checker.check_tree(root_cluster);
+    }
+    // chains of the dirs which were not checked would look lost
+    if checker.complete { checker.check_lost(); }
+    checker.check_fat_copies();
+
+    // flush() may mark the volume clean now
+    if checker.count == 0 { volume.verified.set(true); }
+    Ok(checker.count)
+}
+
+impl Entry {
+    /// The root dir itself, its chain starts from root_cluster
+    fn root() -> Self {
+        let mut name = [0x20; 11];
+        name[0] = b'/';
+        Self {
+            dir_cluster: 0,
+            index: 0,
+            name,
+        }
+    }
+}
+
+struct Bitmap<'b>(&'b mut [u8]);
+
+impl<'b> Bitmap<'b> {
+    fn get(&self, cluster: u32) -> bool {
+        let cluster = cluster as usize;
+        self.0[cluster / 8] & (1 << (cluster % 8)) != 0
+    }
+
+    fn set(&mut self, cluster: u32) {
+        let cluster = cluster as usize;
+        self.0[cluster / 8] |= 1 << (cluster % 8);
+    }
+}
+
+struct Checker<'a, 'b, T, F>
+    where T: BlockDevice,
+          <T as BlockDevice>::Error: core::fmt::Debug,
+          F: FnMut(Problem, bool) {
+    volume: &'a Volume<'a, T>,
+    device: &'a T,
+    bpb: &'a BIOSParameterBlock,
+    fat: FAT<'a, T>,
+    used: Bitmap<'b>,
+    pointed: Bitmap<'b>,
+    repair: Option<LostChains>,
+    reserved: Reserved,
+    report: F,
+    count: usize,
+    complete: bool,
+}
+
+impl<'a, 'b, T, F> Checker<'a, 'b, T, F>
+    where T: BlockDevice,
+          <T as BlockDevice>::Error: core::fmt::Debug,
+          F: FnMut(Problem, bool) {
+    fn report(&mut self, problem: Problem, fixed: bool) {
+        self.count += 1;
+        (self.report)(problem, fixed);
+    }
+
+    fn is_valid(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster <= self.bpb.max_cluster()
+    }
+
+    fn dir_iter(&self, cluster: u32) -> DirIter<'a, T> {
+        DirIter::new(self.device, self.volume.fat(cluster), self.bpb)
+    }
+
+    /// Mark chain as used, Return count of clusters, None if chain is broken
+    fn walk_chain(&mut self, entry: Entry, cluster: u32) -> Option<u32> {
+        let mut previous = 0;
+        let mut cluster = cluster;
+        let mut count = 0;
+
+        loop {
+            if !self.is_valid(cluster) {
+                let fixed = self.cut_chain(previous);
+                self.report(Problem::InvalidCluster { entry, previous, cluster }, fixed);
+                return if fixed { Some(count) } else { None };
+            }
+            if self.used.get(cluster) {
+                let fixed = self.cut_chain(previous);
+                self.report(Problem::CrossLinked { entry, previous, cluster }, fixed);
+                return if fixed { Some(count) } else { None };
+            }
+            self.used.set(cluster);
+            count += 1;
+
+            let next = block_on(self.fat.get(cluster));
+            if self.bpb.fat_type.is_end(next) { return Some(count); }
+            previous = cluster;
+            cluster = next;
+        }
+    }
+
+    /// Mark chain as used without any report, for the chain which is known good
+    fn mark_chain(&mut self, cluster: u32) {
+        let mut cluster = cluster;
+        for _ in 0..self.bpb.max_cluster() {
+            if !self.is_valid(cluster) { break; }
+            self.used.set(cluster);
+            cluster = block_on(self.fat.get(cluster));
+            if self.bpb.fat_type.is_end(cluster) { break; }
+        }
+    }
+
+    /// Check every dir from root dir, explicit stack is used instead of recursion
+    ///
+    /// The used bitmap stops the walk from going into one dir twice, so a loop of dirs ends
+    fn check_tree(&mut self, root_cluster: u32) {
+        // cluster of dir, cluster of its parent AND index of the sub dir item which is checked now
+        let mut stack = [(0, 0, None); MAX_DIR_DEPTH];
+        stack[0] = (root_cluster, root_cluster, None);
+        let mut depth = 1;
+
+        while depth > 0 {
+            let (cluster, parent, from) = stack[depth - 1];
+            let (first, entry) = match self.check_dir(cluster, parent, from) {
+                Some(sub_dir) => sub_dir,
+                None => {
+                    depth -= 1;
+                    continue;
+                }
+            };
+
+            stack[depth - 1].2 = Some(entry.index);
+            if depth == MAX_DIR_DEPTH {
+                self.complete = false;
+                self.report(Problem::DirTooDeep { entry }, false);
+            } else {
+                stack[depth] = (first, cluster, None);
+                depth += 1;
+            }
+        }
+    }
+
+    /// Check items in dir after index from, cluster is 0 for the fixed root dir region
+    ///
+    /// Return first cluster AND entry of the sub dir found, which is checked before the rest items
+    fn check_dir(&mut self, cluster: u32, parent: u32, from: Option<usize>) -> Option<(u32, Entry)> {
+        let mut iter = self.dir_iter(cluster);
+        match from {
+            Some(index) => {
+                block_on(iter.seek(index))?;
+                iter.next();
+            }
+            None => if cluster != self.bpb.root_cluster && !self.has_valid_dot_item(cluster, parent) {
+                self.report(Problem::BadDotItem { cluster }, false);
+            }
+        }
+
+        let cluster_size = self.bpb.sector_per_cluster_usize() * BUFFER_SIZE;
+        // check sum, count, consistency AND index of the first one of long file name items
+        let mut lfn: Option<(u8, usize, bool, usize)> = None;
+
+        while let Some(di) = iter.next() {
+            let index = iter.item_index();
+
+            if di.is_deleted() {
+                if let Some((_, count, _, first)) = lfn.take() {
+                    self.orphan_lfn(cluster, first, count);
+                }
+                continue;
+            }
+
+            if di.is_lfn() {
+                let check_sum = di.check_sum().unwrap();
+                lfn = match (lfn, di.is_name_end().unwrap()) {
+                    (Some((sum, count, consistent, first)), false) => {
+                        Some((sum, count + 1, consistent && sum == check_sum, first))
+                    }
+                    (None, false) => {
+                        self.orphan_lfn(cluster, index, 1);
+                        None
+                    }
+                    (pending, true) => {
+                        if let Some((_, count, _, first)) = pending {
+                            self.orphan_lfn(cluster, first, count);
+                        }
+                        Some((check_sum, 1, true, index))
+                    }
+                };
+                continue;
+            }
+
+            let entry = Entry {
+                dir_cluster: cluster,
+                index,
+                name: di.sfn_name().unwrap_or([0; 11]),
+            };
+
+            if let Some((sum, count, consistent, first)) = lfn.take() {
+                if !consistent || Some(sum) != di.sfn_check_sum() {
+                    let fixed = self.delete_items(cluster, first, count);
+                    self.report(Problem::LfnChecksum { entry, count }, fixed);
+                }
+            }
+
+            let length = di.length().unwrap() as u32;
+            let first = di.cluster();
+            if di.is_file() && first == 0 {
+                if length != 0 {
+                    let fixed = self.set_length(entry, 0);
+                    self.report(Problem::SizeMismatch { entry, length, clusters: 0 }, fixed);
+                }
+                continue;
+            }
+
+            let clusters = match self.walk_chain(entry, first) {
+                Some(clusters) => clusters,
+                None => continue,
+            };
+
+            if di.is_dir() {
+                // long file name items before the sub dir item are done, none is pending
+                return Some((first, entry));
+            } else {
+                // create_file gives one cluster to the empty file
+                let needed = ((length as usize).div_ceil(cluster_size) as u32).max(1);
+                if clusters < needed {
+                    let fixed = self.set_length(entry, clusters * cluster_size as u32);
+                    self.report(Problem::SizeMismatch { entry, length, clusters }, fixed);
+                } else if clusters > needed {
+                    let fixed = self.truncate_chain(first, needed);
+                    self.report(Problem::ExtraClusters { entry, length, clusters }, fixed);
+                }
+            }
+        }
+
+        if let Some((_, count, _, first)) = lfn {
+            self.orphan_lfn(cluster, first, count);
+        }
+        None
+    }
+
+    fn orphan_lfn(&mut self, dir_cluster: u32, first: usize, count: usize) {
+        let entry = Entry {
+            dir_cluster,
+            index: first,
+            name: [0; 11],
+        };
+        let fixed = self.delete_items(dir_cluster, first, count);
+        self.report(Problem::OrphanLfn { entry, count }, fixed);
+    }
+
+    /// Check '.' AND '..' item, '..' points to 0 OR root cluster if parent is root dir
+    fn has_valid_dot_item(&self, cluster: u32, parent: u32) -> bool {
+        let mut buf = [0; BUFFER_SIZE];
+        self.device.read(&mut buf, sector_address(self.bpb.sector(cluster)), 1).unwrap();
+
+        let item_cluster = |item: &[u8]| {
+            ((item[0x15] as u32) << 24)
+                | ((item[0x14] as u32) << 16)
+                | ((item[0x1B] as u32) << 8)
+                | (item[0x1A] as u32)
+        };
+        let is_dot_item = |item: &[u8], name: &[u8]| {
+            item[0..name.len()] == *name
+                && item[name.len()..11].iter().all(|&b| b == 0x20)
+                && item[0x0B] & 0x10 == 0x10
+        };
+
+        let parent_is_root = parent == self.bpb.root_cluster;
+        let dot = &buf[0..32];
+        let dot_dot = &buf[32..64];
+
+        is_dot_item(dot, b".")
+            && item_cluster(dot) == cluster
+            && is_dot_item(dot_dot, b"..")
+            && (item_cluster(dot_dot) == parent
+            || parent_is_root && item_cluster(dot_dot) == 0)
+    }
+
+    /// Find clusters allocated in FAT but not used, report them chain by chain
+    fn check_lost(&mut self) {
+        let max_cluster = self.bpb.max_cluster();
+        let fat_type = self.bpb.fat_type;
+        let is_lost = |checker: &mut Self, cluster: u32| {
+            let value = block_on(checker.fat.get(cluster));
+            !checker.used.get(cluster) && value != 0 && !fat_type.is_bad(value)
+        };
+
+        // mark lost clusters which are pointed to by other lost clusters
+        for cluster in 2..=max_cluster {
+            if !is_lost(self, cluster) { continue; }
+            let next = block_on(self.fat.get(cluster));
+            if !fat_type.is_end(next) && self.is_valid(next) { self.pointed.set(next); }
+        }
+
+        // the first cluster of chain is not pointed to, the chain which is a loop is last
+        for first_pass in [true, false] {
+            for cluster in 2..=max_cluster {
+                if !is_lost(self, cluster) { continue; }
+                if first_pass && self.pointed.get(cluster) { continue; }
+
+                let mut length = 0;
+                let mut next = cluster;
+                loop {
+                    self.used.set(next);
+                    length += 1;
+                    next = block_on(self.fat.get(next));
+                    if fat_type.is_end(next) || !self.is_valid(next) || !is_lost(self, next) {
+                        break;
+                    }
+                }
+                let fixed = self.fix_lost_chain(cluster, length);
+                self.report(Problem::LostChain { cluster, length }, fixed);
+            }
+        }
+    }
+
+    /// Compare every FAT copy with FAT1, sector by sector
+    fn check_fat_copies(&mut self) {
+        let mut fat1 = [0; BUFFER_SIZE];
+        let mut copy = [0; BUFFER_SIZE];
+        let reserved_bytes = match self.bpb.fat_type {
+            FatType::FAT12 => 3,
+            FatType::FAT16 => 4,
+            FatType::FAT32 => 8,
+        };
+
+        for sector in 0..self.bpb.sector_per_fat {
+            let fat1_sector = self.bpb.fat1() + sector;
+            self.device.read(&mut fat1, sector_address(fat1_sector), 1).unwrap();
+            for fat in 1..self.bpb.num_fat {
+                let copy_sector = fat1_sector + fat as u32 * self.bpb.sector_per_fat;
+                self.device.read(&mut copy, sector_address(copy_sector), 1).unwrap();
+                // FAT[0] AND FAT[1] hold media AND dirty flags, don't compare them
+                let start = if sector == 0 { reserved_bytes } else { 0 };
+                if fat1[start..] != copy[start..] {
+                    // FAT1 is written first, so it is the one to trust
+                    let fixed = self.repair.is_some();
+                    if fixed { block_on(self.fat.sync_copies(sector as usize)); }
+                    self.report(Problem::FatMismatch { fat, sector }, fixed);
+                }
+            }
+        }
+    }
+
+    /// Make previous the last cluster of chain, nothing to cut if previous is 0
+    fn cut_chain(&mut self, previous: u32) -> bool {
+        if self.repair.is_none() || previous == 0 { return false; }
+        block_on(self.fat.write(previous, END_OF_CHAIN));
+        true
+    }
+
+    /// Keep count clusters of the good chain, free the others unless they are kept as reserved
+    fn truncate_chain(&mut self, first: u32, count: u32) -> bool {
+        if self.repair.is_none() || self.reserved == Reserved::Keep { return false; }
+        let mut last = first;
+        for _ in 1..count { last = block_on(self.fat.get(last)); }
+        let rest = block_on(self.fat.get(last));
+        block_on(self.fat.write(last, END_OF_CHAIN));
+        block_on(self.fat.free_chain(rest));
+        true
+    }
+
+    /// Delete count items from index first in the dir
+    fn delete_items(&mut self, dir_cluster: u32, first: usize, count: usize) -> bool {
+        if self.repair.is_none() { return false; }
+        for index in first..first + count {
+            let mut iter = self.dir_iter(dir_cluster);
+            if block_on(iter.seek(index)).is_none() { return false; }
+            iter.set_deleted();
+            block_on(iter.update());
+        }
+        true
+    }
+
+    /// Set file length of the short name item
+    fn set_length(&mut self, entry: Entry, length: u32) -> bool {
+        if self.repair.is_none() { return false; }
+        let mut iter = self.dir_iter(entry.dir_cluster);
+        let mut di = match block_on(iter.seek(entry.index)) {
+            Some(di) => di,
+            None => return false,
+        };
+        di.set_file_length(length as usize);
+        if block_on(iter.update_item(&di.bytes())).is_err() { return false; }
+        block_on(iter.update());
+        true
+    }
+
+    /// Free OR recover the lost chain, the last cluster may point to used cluster OR back to chain
+    fn fix_lost_chain(&mut self, first: u32, length: u32) -> bool {
+        let lost = match self.repair {
+            Some(lost) => lost,
+            None => return false,
+        };
+
+        if lost == LostChains::Recover {
+            let mut last = first;
+            for _ in 1..length { last = block_on(self.fat.get(last)); }
+            if !self.bpb.fat_type.is_end(block_on(self.fat.get(last))) {
+                block_on(self.fat.write(last, END_OF_CHAIN));
+            }
+            if self.recover(first, length).is_ok() { return true; }
+        }
+
+        let mut cluster = first;
+        for _ in 0..length {
+            let next = block_on(self.fat.get(cluster));
+            block_on(self.fat.write(cluster, 0));
+            cluster = next;
+        }
+        true
+    }
+
+    /// Add FILExxxx.CHK item which points to the chain into FOUND.000 dir
+    fn recover(&mut self, first: u32, length: u32) -> Result<(), DirError> {
+        let mut root = self.volume.root_dir();
+        let found = match root.cd(FOUND_DIR) {
+            Ok(dir) => dir,
+            Err(_) => {
+                root.create_dir(FOUND_DIR)?;
+                root.cd(FOUND_DIR)?
+            }
+        };
+        let result = Self::add_found_item(&found, first, length);
+
+        // the dirs may get new clusters, FAT buffer is out of date too
+        self.fat = self.volume.fat(0);
+        if self.bpb.root_cluster != 0 { self.mark_chain(self.bpb.root_cluster); }
+        self.mark_chain(found.detail.cluster());
+        result
+    }
+
+    fn add_found_item(found: &Dir<T>, first: u32, length: u32) -> Result<(), DirError> {
+        let cluster_size = found.volume.bpb.sector_per_cluster_usize() * BUFFER_SIZE;
+        let mut name = *b"file0000.chk";
+
+        for n in 0..10000 {
+            name[4] = b'0' + (n / 1000) as u8;
+            name[5] = b'0' + (n / 100 % 10) as u8;
+            name[6] = b'0' + (n / 10 % 10) as u8;
+            name[7] = b'0' + (n % 10) as u8;
+            let name = core::str::from_utf8(&name).unwrap();
+            if found.exist(name).is_some() { continue; }
+
+            let length = (length as u64 * cluster_size as u64).min(u32::MAX as u64);
+            let mut di = DirectoryItem::new_sfn(first, name, OpType::File);
+            di.set_file_length(length as usize);
+            return block_on(found.write_directory_item(di));
+        }
+        Err(DirError::FileHasExist)
+    }
+}
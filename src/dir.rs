@@ -1,4 +1,8 @@
 use block_device::BlockDevice;
+use crate::async_device::{
+    AsyncBlockDevice,
+    block_on,
+};
 use crate::bpb::BIOSParameterBlock;
 use crate::directory_item::DirectoryItem;
 use crate::BUFFER_SIZE;
@@ -8,10 +12,24 @@ use crate::tool::{
     get_count_of_lfn,
     get_lfn_index,
     generate_checksum,
+    sector_address,
 };
 use crate::directory_item::NameType;
-use crate::file::File;
-use crate::fat::FAT;
+use crate::file::{
+    File,
+    FileError,
+    OpenOptions,
+    WriteType,
+};
+use crate::fat::{AllocError, FAT};
+use crate::volume::Volume;
+use crate::handle::{
+    DirHandle,
+    Position,
+};
+
+/// Name Of The Temporary File Which replace_file() Writes To
+pub const REPLACE_TEMP_FILE: &str = "~replace.tmp";
 
 /// Define DirError
 #[derive(Debug, PartialOrd, PartialEq)]
@@ -21,6 +39,35 @@ pub enum DirError {
     IllegalChar,
     DirHasExist,
     FileHasExist,
+    RootDirFull,
+    ReadOnly,
+    Corrupted,
+    VolumeFull,
+    NotContiguous,
+    WriteError,
+    FileIsOpen,
+    TooManyOpenFiles,
+    InvalidOptions,
+}
+
+impl DirError {
+    fn from_alloc(error: AllocError) -> DirError {
+        match error {
+            AllocError::VolumeFull => DirError::VolumeFull,
+            AllocError::NotContiguous => DirError::NotContiguous,
+        }
+    }
+
+    fn from_file(error: FileError) -> DirError {
+        match error {
+            FileError::ReadOnly => DirError::ReadOnly,
+            FileError::Corrupted => DirError::Corrupted,
+            FileError::VolumeFull => DirError::VolumeFull,
+            FileError::NotContiguous => DirError::NotContiguous,
+            FileError::BufTooSmall | FileError::WriteError
+            | FileError::NotReadable | FileError::NotWritable => DirError::WriteError,
+        }
+    }
 }
 
 /// Define Operation Type
@@ -30,73 +77,219 @@ pub enum OpType {
     File,
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Dir Borrows The Volume, Copy Of It Is Cheap
+#[derive(Debug)]
 pub struct Dir<'a, T>
-    where T: BlockDevice + Clone + Copy,
-          <T as BlockDevice>::Error: core::fmt::Debug {
-    pub(crate) device: T,
-    pub(crate) bpb: &'a BIOSParameterBlock,
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    pub(crate) volume: &'a Volume<'a, T>,
+    pub(crate) position: Option<Position>,
     pub(crate) detail: DirectoryItem,
-    pub(crate) fat: FAT<T>,
+    pub(crate) fat: FAT<'a, T>,
+}
+
+impl<'a, T> Clone for Dir<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    fn clone(&self) -> Self {
+        *self
+    }
 }
 
+impl<'a, T> Copy for Dir<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {}
+
 impl<'a, T> Dir<'a, T>
-    where T: BlockDevice + Clone + Copy,
+    where T: BlockDevice,
           <T as BlockDevice>::Error: core::fmt::Debug {
     /// Delete Dir
     pub fn delete_dir(&mut self, dir: &str) -> Result<(), DirError> {
-        self.delete(dir, OpType::Dir)
+        block_on(self.delete(dir, OpType::Dir))
     }
 
     /// Delete File
     pub fn delete_file(&mut self, file: &str) -> Result<(), DirError> {
-        self.delete(file, OpType::File)
+        block_on(self.delete(file, OpType::File))
     }
 
     /// Create Dir
     pub fn create_dir(&mut self, dir: &str) -> Result<(), DirError> {
-        self.create(dir, OpType::Dir)
+        block_on(self.create(dir, OpType::Dir, 1, false)).map(|_| ())
     }
 
     /// Create File
     pub fn create_file(&mut self, file: &str) -> Result<(), DirError> {
-        self.create(file, OpType::File)
+        block_on(self.create(file, OpType::File, 1, false)).map(|_| ())
+    }
+
+    /// Create File With Clusters Reserved For length Bytes, Return Whether They Are Contiguous
+    ///
+    /// File length is 0, see File::reserve
+    pub fn create_file_with_size(&mut self, file: &str, length: usize, contiguous: bool) -> Result<bool, DirError> {
+        block_on(self.create_with_size(file, length, contiguous))
+    }
+
+    /// Replace Content Of File, write Gets An Empty Temporary File To Write The New Content
+    ///
+    /// The file is switched to the new chain in one sector write AND the old chain is freed,
+    /// so it has old OR new content after a power loss, never a mix.
+    /// A temporary file left by a power loss is never deleted, it may be a file of the user,
+    /// FileHasExist is returned till it is deleted OR renamed. The temporary file itself can't be replaced.
+    /// If write fails OR the temporary file gets no slot in open file table,
+    /// the temporary file is deleted AND the file keeps old content
+    pub fn replace_file<F>(&mut self, file: &str, write: F) -> Result<(), DirError>
+        where F: FnOnce(&mut File<'a, T>) -> Result<(), FileError> {
+        block_on(self.replace(file, async |temp: &mut File<'a, T>| write(temp)))
     }
 
     /// Open File, Return File<T> Type
     pub fn open_file(&self, file: &str) -> Result<File<'a, T>, DirError> {
+        block_on(self.open(file))
+    }
+
+    /// Cd Dir, Return Dir<T> Type
+    pub fn cd(&self, dir: &str) -> Result<Dir<'a, T>, DirError> {
+        block_on(self.enter(dir))
+    }
+
+    /// Check if file or dir is exist or not, Return Option Type
+    pub fn exist(&self, value: &str) -> Option<DirectoryItem> {
+        let mut iter = self.iter();
+        block_on(self.exist_iter_async(&mut iter, value))
+    }
+
+    /// Check if file or dir is exist or not through DirIter<T>, Return Option Type
+    pub fn exist_iter(&self, iter: &mut DirIter<T>, value: &str) -> Option<DirectoryItem> {
+        block_on(self.exist_iter_async(iter, value))
+    }
+}
+
+impl<'a, T> Dir<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    /// Delete Dir, Async Version Of delete_dir()
+    pub async fn delete_dir_async(&mut self, dir: &str) -> Result<(), DirError> {
+        self.delete(dir, OpType::Dir).await
+    }
+
+    /// Delete File, Async Version Of delete_file()
+    pub async fn delete_file_async(&mut self, file: &str) -> Result<(), DirError> {
+        self.delete(file, OpType::File).await
+    }
+
+    /// Create Dir, Async Version Of create_dir()
+    pub async fn create_dir_async(&mut self, dir: &str) -> Result<(), DirError> {
+        self.create(dir, OpType::Dir, 1, false).await.map(|_| ())
+    }
+
+    /// Create File, Async Version Of create_file()
+    pub async fn create_file_async(&mut self, file: &str) -> Result<(), DirError> {
+        self.create(file, OpType::File, 1, false).await.map(|_| ())
+    }
+
+    /// Create File With Clusters Reserved, Async Version Of create_file_with_size()
+    pub async fn create_file_with_size_async(&mut self, file: &str, length: usize, contiguous: bool) -> Result<bool, DirError> {
+        self.create_with_size(file, length, contiguous).await
+    }
+
+    /// Replace Content Of File, Async Version Of replace_file(), write Is An Async Closure
+    pub async fn replace_file_async<F>(&mut self, file: &str, write: F) -> Result<(), DirError>
+        where F: AsyncFnOnce(&mut File<'a, T>) -> Result<(), FileError> {
+        self.replace(file, write).await
+    }
+
+    /// Open File, Async Version Of open_file()
+    pub async fn open_file_async(&self, file: &str) -> Result<File<'a, T>, DirError> {
+        self.open(file).await
+    }
+
+    /// Cd Dir, Async Version Of cd()
+    pub async fn cd_async(&self, dir: &str) -> Result<Dir<'a, T>, DirError> {
+        self.enter(dir).await
+    }
+
+    /// Check if file or dir is exist or not, Async Version Of exist()
+    pub async fn exist_async(&self, value: &str) -> Option<DirectoryItem> {
+        let mut iter = self.iter();
+        self.exist_iter_async(&mut iter, value).await
+    }
+
+    /// Get Handle Which Borrows Nothing, See Volume::dir()
+    pub fn handle(&self) -> DirHandle {
+        DirHandle {
+            id: self.volume.bpb.id,
+            position: self.position,
+        }
+    }
+
+    async fn open(&self, file: &str) -> Result<File<'a, T>, DirError> {
         if is_illegal(file) { return Err(DirError::IllegalChar); }
-        match self.exist(file) {
+        let mut iter = self.iter();
+        match self.find_item_iter(&mut iter, file).await? {
             None => Err(DirError::NoMatchFile),
             Some(di) => if di.is_file() {
-                let fat = FAT::new(di.cluster(),
-                                   self.device,
-                                   self.bpb.fat1());
-                Ok(File::<T> {
-                    device: self.device,
-                    bpb: self.bpb,
-                    dir_cluster: self.detail.cluster(),
-                    detail: di,
-                    fat,
-                })
+                self.file_at(self.position_of(&iter), di)
             } else {
                 Err(DirError::NoMatchFile)
             }
         }
     }
 
-    /// Cd Dir, Return Dir<T> Type
-    pub fn cd(&self, dir: &str) -> Result<Dir<'a, T>, DirError> {
+    /// Open File With Options, Dir Is Scanned Once Even If The File Is Created
+    pub(crate) async fn open_with(&mut self, file: &str, options: OpenOptions) -> Result<File<'a, T>, DirError> {
+        if !options.is_valid() { return Err(DirError::InvalidOptions); }
+        if options.is_writable() && self.volume.is_read_only() { return Err(DirError::ReadOnly); }
+        if is_illegal(file) { return Err(DirError::IllegalChar); }
+        let mut iter = self.iter();
+
+        let mut opened = match self.find_item_iter(&mut iter, file).await? {
+            Some(di) if options.create_new => return Err(if di.is_dir() {
+                DirError::DirHasExist
+            } else {
+                DirError::FileHasExist
+            }),
+            Some(di) if di.is_file() => {
+                let mut opened = self.file_at(self.position_of(&iter), di)?;
+                if options.truncate {
+                    opened.write_async(&[], WriteType::OverWritten).await.map_err(DirError::from_file)?;
+                }
+                opened
+            }
+            Some(_) => return Err(DirError::NoMatchFile),
+            None if options.is_creating() => {
+                let (_, di, position) = self.create_in(&mut iter, file, OpType::File, 1, false).await?;
+                self.file_at(position, di)?
+            }
+            None => return Err(DirError::NoMatchFile),
+        };
+        opened.options = options;
+        Ok(opened)
+    }
+
+    /// Get File Of The Item At The Position, It Takes A Slot In Open File Table
+    fn file_at(&self, position: Position, di: DirectoryItem) -> Result<File<'a, T>, DirError> {
+        let slot = self.volume.open_slot(position, di.cluster(), di.length().unwrap())?;
+        Ok(File::<T> {
+            volume: self.volume,
+            position,
+            detail: di,
+            fat: self.fat.with_start(di.cluster()),
+            slot,
+            options: OpenOptions::READ_WRITE,
+        })
+    }
+
+    async fn enter(&self, dir: &str) -> Result<Dir<'a, T>, DirError> {
         if is_illegal(dir) { return Err(DirError::IllegalChar); }
-        match self.exist(dir) {
+        let mut iter = self.iter();
+        match self.find_item_iter(&mut iter, dir).await? {
             None => Err(DirError::NoMatchDir),
             Some(di) => if di.is_dir() {
-                let fat = FAT::new(di.cluster(),
-                                   self.device,
-                                   self.bpb.fat1());
+                let fat = self.fat.with_start(di.cluster());
                 Ok(Self {
-                    device: self.device,
-                    bpb: self.bpb,
+                    volume: self.volume,
+                    position: Some(self.position_of(&iter)),
                     detail: di,
                     fat,
                 })
@@ -106,191 +299,354 @@ impl<'a, T> Dir<'a, T>
         }
     }
 
-    /// Check if file or dir is exist or not, Return Option Type
-    pub fn exist(&self, value: &str) -> Option<DirectoryItem> {
-        let mut iter = DirIter::new(self.device, self.fat, self.bpb);
-
+    async fn exist_iter_async(&self, iter: &mut DirIter<'_, T>, value: &str) -> Option<DirectoryItem> {
         match sfn_or_lfn(value) {
-            NameType::SFN => iter.find(|d| d.sfn_equal(value)),
-            NameType::LFN => self.find_lfn(&mut iter, value),
+            NameType::SFN => {
+                while let Some(d) = iter.next_item().await {
+                    if d.sfn_equal(value) { return Some(d); }
+                }
+                None
+            }
+            NameType::LFN => self.find_lfn(iter, value).await,
         }
     }
 
-    /// Check if file or dir is exist or not through DirIter<T>, Return Option Type
-    pub fn exist_iter(&self, iter: &mut DirIter<T>, value: &str) -> Option<DirectoryItem> {
-        match sfn_or_lfn(value) {
-            NameType::SFN => iter.find(|d| d.sfn_equal(value)),
-            NameType::LFN => self.find_lfn(iter, value),
-        }
+    /// Find Item, Return Corrupted Error If Dir Chain Is Broken
+    async fn find_item_iter(&self, iter: &mut DirIter<'_, T>, value: &str) -> Result<Option<DirectoryItem>, DirError> {
+        let di = self.exist_iter_async(iter, value).await;
+        if di.is_none() && iter.is_corrupted() { return Err(DirError::Corrupted); }
+        Ok(di)
     }
 
     /// Find Long File Name Item, Return Option Type
-    fn find_lfn(&self, iter: &mut DirIter<T>, value: &str) -> Option<DirectoryItem> {
+    async fn find_lfn(&self, iter: &mut DirIter<'_, T>, value: &str) -> Option<DirectoryItem> {
         let count = get_count_of_lfn(value);
         let mut index = get_lfn_index(value, count);
         let mut has_match = true;
 
-        let result = iter.find(|d| {
+        let mut result = None;
+        while let Some(d) = iter.next_item().await {
             if d.is_lfn()
                 && d.count_of_name().unwrap() == count
                 && d.is_name_end().unwrap()
                 && d.lfn_equal(&value[index..]) {
-                true
-            } else { false }
-        });
+                result = Some(d);
+                break;
+            }
+        }
 
-        if let Some(_) = result {
+        if result.is_some() {
             for c in (1..count).rev() {
                 let value = &value[0..index];
                 index = get_lfn_index(value, c);
 
-                let next = iter.next().unwrap();
-                if next.lfn_equal(&value[index..]) {
-                    continue;
-                } else {
-                    has_match = false;
-                    break;
+                match iter.next_item().await {
+                    Some(next) if next.lfn_equal(&value[index..]) => continue,
+                    _ => {
+                        has_match = false;
+                        break;
+                    }
                 }
             }
         }
 
-        if has_match { iter.next() } else { None }
+        if has_match { iter.next_item().await } else { None }
+    }
+
+    /// Create file with clusters for length bytes, at least one
+    async fn create_with_size(&mut self, file: &str, length: usize, contiguous: bool) -> Result<bool, DirError> {
+        let cluster_size = self.volume.bpb.sector_per_cluster_usize() * BUFFER_SIZE;
+        let clusters = core::cmp::max(length.div_ceil(cluster_size), 1);
+        self.create(file, OpType::File, clusters as u32, contiguous).await
+    }
+
+    /// Basic Replace Function, see replace_file()
+    async fn replace<F>(&mut self, file: &str, write: F) -> Result<(), DirError>
+        where F: AsyncFnOnce(&mut File<'a, T>) -> Result<(), FileError> {
+        if self.volume.is_read_only() { return Err(DirError::ReadOnly); }
+        if file == REPLACE_TEMP_FILE { return Err(DirError::FileHasExist); }
+        let mut target = self.open(file).await?;
+
+        let mut iter = self.iter();
+        if self.find_item_iter(&mut iter, REPLACE_TEMP_FILE).await?.is_some() {
+            return Err(DirError::FileHasExist);
+        }
+        let (_, di, position) = self.create_in(&mut iter, REPLACE_TEMP_FILE, OpType::File, 1, false).await?;
+        let mut temp = match self.file_at(position, di) {
+            Ok(temp) => temp,
+            Err(e) => {
+                // no slot in open file table, the temporary file is not left behind
+                self.delete(REPLACE_TEMP_FILE, OpType::File).await?;
+                return Err(e);
+            }
+        };
+        let written = write(&mut temp).await;
+        let length = temp.length();
+        // temporary file is not open while its item is removed
+        drop(temp);
+        if let Err(e) = written {
+            self.delete(REPLACE_TEMP_FILE, OpType::File).await?;
+            return Err(DirError::from_file(e));
+        }
+
+        // temporary item goes first, a power loss in between only loses the new chain
+        let new = self.remove_item(REPLACE_TEMP_FILE, OpType::File).await?;
+        let old = target.cluster();
+        target.update_item(new.cluster(), length).await;
+        self.fat.free_chain(old).await;
+        Ok(())
     }
 
     /// Basic Create Function
-    fn create(&mut self, value: &str, create_type: OpType) -> Result<(), DirError> {
+    async fn create(&mut self, value: &str, create_type: OpType, clusters: u32, contiguous: bool) -> Result<bool, DirError> {
+        if self.volume.is_read_only() { return Err(DirError::ReadOnly); }
         if is_illegal(value) { return Err(DirError::IllegalChar); }
-        if let Some(_) = self.exist(value) {
+        let mut iter = self.iter();
+        if self.find_item_iter(&mut iter, value).await?.is_some() {
             return match create_type {
                 OpType::Dir => Err(DirError::DirHasExist),
                 OpType::File => Err(DirError::FileHasExist)
             };
         }
+        self.create_in(&mut iter, value, create_type, clusters, contiguous).await
+            .map(|(is_contiguous, _, _)| is_contiguous)
+    }
 
-        let blank_cluster = self.fat.blank_cluster();
-        self.fat.write(blank_cluster, 0x0FFFFFFF);
+    /// Create Item At The End Of Dir, Return Whether Clusters Are Contiguous, The Item AND Its Position
+    ///
+    /// iter didn't find the name, it goes on to the end, so the dir isn't scanned again
+    async fn create_in(&mut self, iter: &mut DirIter<'a, T>, value: &str, create_type: OpType, clusters: u32, contiguous: bool)
+                       -> Result<(bool, DirectoryItem, Position), DirError> {
+        let needed_items = match sfn_or_lfn(value) {
+            NameType::SFN => 1,
+            NameType::LFN => get_count_of_lfn(value) + 1,
+        };
+        // only the fixed root dir region can be full
+        iter.seek_end().await;
+        if self.fat.is_root_region() && iter.free_items() < needed_items { return Err(DirError::RootDirFull); }
+        self.volume.mark_dirty().await;
+
+        let (blank_cluster, is_contiguous) = self.fat.allocate(0, clusters, contiguous).await
+            .map_err(DirError::from_alloc)?;
+
+        // dir is ready before any item points to it
+        if let OpType::Dir = create_type {
+            self.clean_cluster_data(blank_cluster).await;
+            self.add_dot_item(blank_cluster).await;
+        }
 
-        match sfn_or_lfn(value) {
+        let written = match sfn_or_lfn(value) {
             NameType::SFN => {
                 let di = DirectoryItem::new_sfn(blank_cluster,
                                                 value,
                                                 create_type);
-                self.write_directory_item(di);
+                self.write_directory_items(iter, core::iter::once(di)).await.map(|_| di)
             }
             NameType::LFN => {
                 let sfn = "unsupported".as_bytes();
                 let check_sum = generate_checksum(sfn);
                 let count = get_count_of_lfn(value);
-                let mut lfn_index = get_lfn_index(value, count);
+                let mut lfn_end = value.len();
 
-                let di = DirectoryItem::new_lfn((count as u8) | (1 << 6),
-                                                check_sum,
-                                                &value[lfn_index..]);
-
-                self.write_directory_item(di);
-
-                for c in (1..count).rev() {
-                    let value = &value[0..lfn_index];
-                    lfn_index = get_lfn_index(value, c);
-                    let di = DirectoryItem::new_lfn(c as u8,
+                // last fragment comes first, SFN item follows the fragments
+                let lfn = (1..=count).rev().map(|c| {
+                    let lfn_index = get_lfn_index(&value[0..lfn_end], c);
+                    let order = if c == count { (c as u8) | (1 << 6) } else { c as u8 };
+                    let di = DirectoryItem::new_lfn(order,
                                                     check_sum,
-                                                    &value[lfn_index..]);
-                    self.write_directory_item(di);
-                }
-
+                                                    &value[lfn_index..lfn_end]);
+                    lfn_end = lfn_index;
+                    di
+                });
                 let di = DirectoryItem::new_sfn_bytes(blank_cluster,
                                                       sfn,
                                                       create_type);
-                self.write_directory_item(di);
+                self.write_directory_items(iter, lfn.chain(core::iter::once(di))).await.map(|_| di)
             }
-        }
+        };
+        // no item points to the clusters, they are freed
+        let di = match written {
+            Ok(di) => di,
+            Err(e) => {
+                self.fat.free_chain(blank_cluster).await;
+                return Err(e);
+            }
+        };
+        // SFN item is the last one written, it is read back the same way open() reads it
+        Ok((is_contiguous, DirectoryItem::from_buf(&di.bytes()), self.position_of(iter)))
+    }
 
-        if let OpType::Dir = create_type {
-            self.clean_cluster_data(blank_cluster);
-            self.add_dot_item(blank_cluster);
-        }
+    /// Basic Delete Function
+    async fn delete(&mut self, value: &str, delete_type: OpType) -> Result<(), DirError> {
+        // items are deleted before clusters are freed, a power loss only loses clusters
+        let di = self.remove_item(value, delete_type).await?;
+        if let OpType::Dir = delete_type { self.delete_in_dir(di.cluster()).await; }
+        self.fat.free_chain(di.cluster()).await;
         Ok(())
     }
 
-    /// Basic Delete Function
-    fn delete(&mut self, value: &str, delete_type: OpType) -> Result<(), DirError> {
+    /// Mark Items Of File OR Dir Deleted, Its Clusters Are Not Freed
+    async fn remove_item(&mut self, value: &str, delete_type: OpType) -> Result<DirectoryItem, DirError> {
+        if self.volume.is_read_only() { return Err(DirError::ReadOnly); }
         if is_illegal(value) { return Err(DirError::IllegalChar); }
-        let mut iter = DirIter::new(self.device, self.fat, self.bpb);
+        let mut iter = self.iter();
 
-        match self.exist_iter(&mut iter, value) {
+        let di = match self.find_item_iter(&mut iter, value).await? {
             None => return match delete_type {
                 OpType::Dir => Err(DirError::NoMatchDir),
                 OpType::File => Err(DirError::NoMatchFile)
             },
-            Some(di) => {
-                match delete_type {
-                    OpType::Dir if di.is_file() => return Err(DirError::NoMatchDir),
-                    OpType::File if di.is_dir() => return Err(DirError::NoMatchFile),
-                    OpType::Dir => self.delete_in_dir(di.cluster()),
-                    OpType::File => ()
-                }
-                self.fat.write(di.cluster(), 0);
+            Some(di) => match delete_type {
+                OpType::Dir if di.is_file() => return Err(DirError::NoMatchDir),
+                OpType::File if di.is_dir() => return Err(DirError::NoMatchFile),
+                _ => di
             }
+        };
+        let is_open = match delete_type {
+            OpType::Dir => self.has_open_file(di.cluster()).await,
+            OpType::File => {
+                let position = self.position_of(&iter);
+                self.volume.open_positions().any(|p| p == position)
+            }
+        };
+        if is_open { return Err(DirError::FileIsOpen); }
+        self.volume.mark_dirty().await;
+
+        let count = match sfn_or_lfn(value) {
+            NameType::SFN => 0,
+            NameType::LFN => get_count_of_lfn(value),
+        };
+        for _ in 0..=count {
+            iter.previous().await;
+            iter.set_deleted();
+            iter.update().await;
         }
+        Ok(di)
+    }
 
-        match sfn_or_lfn(value) {
-            NameType::SFN => {
-                iter.previous();
-                iter.set_deleted();
-                iter.update();
+    /// Free Chains Of ALL File And Dir Which Included Deleted Dir
+    ///
+    /// Clusters of the dir are freed too, so its items are left as they are.
+    /// It goes down to a dir which has no used sub dir, frees it AND starts from the top again,
+    /// a freed chain marks the sub dir done, so no stack is needed
+    async fn delete_in_dir(&self, cluster: u32) {
+        let mut fat = self.fat.with_start(cluster);
+        loop {
+            let mut current = cluster;
+            let mut depth = 0;
+            while let Some(sub_dir) = self.used_sub_dir(current).await {
+                // dir which contains its parent loops forever
+                depth += 1;
+                if depth > self.volume.bpb.max_cluster() { return; }
+                current = sub_dir;
             }
-            NameType::LFN => {
-                let count = get_count_of_lfn(value);
-                for _ in 0..=count {
-                    iter.previous();
-                    iter.set_deleted();
-                    iter.update();
-                }
+
+            let mut iter = DirIter::new(&self.volume.device, fat.with_start(current), &self.volume.bpb);
+            while let Some(d) = iter.next_item().await {
+                if !d.is_deleted() && d.is_file() { fat.free_chain(d.cluster()).await; }
             }
+            if current == cluster { return; }
+            fat.free_chain(current).await;
         }
-        Ok(())
     }
 
-    /// Delete ALL File And Dir Which Included Deleted Dir
-    fn delete_in_dir(&self, cluster: u32) {
-        let fat_offset = self.bpb.fat1();
-        let fat = FAT::new(cluster, self.device, fat_offset);
-        let mut iter = DirIter::new(self.device, fat, self.bpb);
-        loop {
-            if let Some(d) = iter.next() {
-                if d.is_dir() { self.delete_in_dir(d.cluster()); }
-                if d.is_deleted() { continue; }
-                iter.previous();
-                iter.set_deleted();
-                iter.update();
-                iter.next();
-            } else {
-                break;
+    /// Find sub dir of the dir whose chain is not freed yet
+    async fn used_sub_dir(&self, cluster: u32) -> Option<u32> {
+        let mut fat = self.fat.with_start(cluster);
+        let mut iter = DirIter::new(&self.volume.device, fat, &self.volume.bpb);
+        while let Some(d) = iter.next_item().await {
+            if d.is_deleted() || !d.is_dir() { continue; }
+            let sub_dir = d.cluster();
+            if sub_dir < 2 || sub_dir > self.volume.bpb.max_cluster() { continue; }
+            if fat.get(sub_dir).await != 0 { return Some(sub_dir); }
+        }
+        None
+    }
+
+    /// Check if a file in the dir OR in its sub dirs is in open file table
+    ///
+    /// Dir of every open file is followed up by its '..' item
+    async fn has_open_file(&self, cluster: u32) -> bool {
+        let root = self.volume.bpb.root_cluster;
+        let max_cluster = self.volume.bpb.max_cluster();
+        for position in self.volume.open_positions() {
+            let mut current = position.dir_cluster;
+            let mut depth = 0;
+            while current != root && current >= 2 && current <= max_cluster {
+                if current == cluster { return true; }
+                // dir which contains its parent loops forever
+                depth += 1;
+                if depth > max_cluster { break; }
+                current = self.parent(current).await;
             }
         }
+        false
+    }
+
+    /// Get cluster of the parent dir from '..' item, 0 is root dir
+    async fn parent(&self, cluster: u32) -> u32 {
+        let mut buffer = [0; BUFFER_SIZE];
+        let sector = self.volume.bpb.sector(cluster);
+        self.volume.device.read(&mut buffer, sector_address(sector), 1).await.unwrap();
+        DirectoryItem::from_buf(&buffer[32..64]).cluster()
+    }
+
+    /// Get position of the item which was returned last by iter
+    fn position_of(&self, iter: &DirIter<T>) -> Position {
+        Position {
+            dir_cluster: self.detail.cluster(),
+            index: iter.item_index(),
+        }
+    }
+
+    /// Iterate items of the dir
+    fn iter(&self) -> DirIter<'a, T> {
+        DirIter::new(&self.volume.device, self.fat, &self.volume.bpb)
     }
 
     /// Write Directory Item
-    fn write_directory_item(&self, di: DirectoryItem) {
-        let mut iter = DirIter::new(self.device, self.fat, self.bpb);
-        iter.find(|_| false);
-        iter.update_item(&di.bytes());
-        iter.update();
+    pub(crate) async fn write_directory_item(&self, di: DirectoryItem) -> Result<(), DirError> {
+        let mut iter = self.iter();
+        self.write_directory_items(&mut iter, core::iter::once(di)).await
+    }
+
+    /// Write Directory Items In A Row At The End Of Dir, iter Goes On From Where It Is
+    ///
+    /// Dir is scanned once, each sector is written once after its items are filled
+    async fn write_directory_items<I>(&self, iter: &mut DirIter<'a, T>, items: I) -> Result<(), DirError>
+        where I: Iterator<Item=DirectoryItem> {
+        iter.seek_end().await;
+        if iter.is_corrupted() { return Err(DirError::Corrupted); }
+        for (written, di) in items.enumerate() {
+            if let Err(e) = iter.update_item(&di.bytes()).await {
+                // dir can't grow, long name items written before are cleared, the dir ends there again
+                for _ in 0..written {
+                    iter.previous().await;
+                    iter.update_item(&[0; 32]).await?;
+                    iter.update().await;
+                }
+                return Err(e);
+            }
+            iter.next_slot().await;
+        }
+        // sector which is filled to its end has been written
+        if iter.index != 0 { iter.update().await; }
+        Ok(())
     }
 
     /// Clean Sectors In Cluster, To Avoid Dirty Data
-    fn clean_cluster_data(&self, cluster: u32) {
-        let spc = self.bpb.sector_per_cluster_usize();
+    async fn clean_cluster_data(&self, cluster: u32) {
+        let spc = self.volume.bpb.sector_per_cluster_usize();
         for i in 0..spc {
-            let offset = self.bpb.offset(cluster) + i * BUFFER_SIZE;
-            self.device.write(&[0; BUFFER_SIZE],
-                              offset,
-                              1).unwrap();
+            let sector = self.volume.bpb.sector(cluster) + i as u32;
+            self.volume.device.write(&[0; BUFFER_SIZE],
+                              sector_address(sector),
+                              1).await.unwrap();
         }
     }
 
     /// Add '.' AND '..' Item
-    fn add_dot_item(&self, cluster: u32) {
+    async fn add_dot_item(&self, cluster: u32) {
         let mut buffer = [0; BUFFER_SIZE];
 
         let mut value = [0x20; 11];
@@ -298,75 +654,152 @@ impl<'a, T> Dir<'a, T>
         let mut di = DirectoryItem::new_sfn_bytes(cluster, &value, OpType::Dir);
         buffer[0..32].copy_from_slice(&di.bytes());
         value[1] = b'.';
-        di = DirectoryItem::new_sfn_bytes(self.detail.cluster(), &value, OpType::Dir);
+        // '..' item points to 0 if parent is root dir
+        let parent = if self.detail.cluster() == self.volume.bpb.root_cluster {
+            0
+        } else {
+            self.detail.cluster()
+        };
+        di = DirectoryItem::new_sfn_bytes(parent, &value, OpType::Dir);
         buffer[32..64].copy_from_slice(&di.bytes());
 
-        let offset = self.bpb.offset(cluster);
-        self.device.write(&buffer, offset, 1).unwrap();
+        let sector = self.volume.bpb.sector(cluster);
+        self.volume.device.write(&buffer, sector_address(sector), 1).await.unwrap();
     }
 }
 
 /// To Iterate Dir
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug)]
 pub struct DirIter<'a, T>
-    where T: BlockDevice + Clone + Copy,
-          <T as BlockDevice>::Error: core::fmt::Debug {
-    device: T,
-    fat: FAT<T>,
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    device: &'a T,
+    fat: FAT<'a, T>,
     bpb: &'a BIOSParameterBlock,
-    offset: usize,
+    sector: u32,
     sector_offset: usize,
     index: usize,
+    count: usize,
+    started: bool,
     buffer: [u8; BUFFER_SIZE],
 }
 
-impl<'a, T> DirIter<'a, T>
-    where T: BlockDevice + Clone + Copy,
-          <T as BlockDevice>::Error: core::fmt::Debug {
-    pub(crate) fn new(device: T, fat: FAT<T>, bpb: &BIOSParameterBlock)
-                      -> DirIter<T> {
-        let mut fat = fat;
-        fat.next();
+impl<'a, T> Clone for DirIter<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
 
+impl<'a, T> Copy for DirIter<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {}
+
+impl<'a, T> DirIter<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    /// Nothing is read here, the first cluster is followed by the first next_item()
+    pub(crate) fn new(device: &'a T, fat: FAT<'a, T>, bpb: &'a BIOSParameterBlock)
+                      -> DirIter<'a, T> {
         DirIter::<T> {
             device,
             fat,
             bpb,
-            offset: bpb.offset(fat.current_cluster),
+            sector: 0,
             sector_offset: 0,
             index: 0,
+            count: 0,
+            started: false,
             buffer: [0; BUFFER_SIZE],
         }
     }
 
-    fn offset_value(&self) -> usize {
-        self.offset + self.sector_offset * BUFFER_SIZE
+    async fn start(&mut self) {
+        self.started = true;
+        if self.fat.is_root_region() {
+            self.sector = self.bpb.root_dir_sector();
+        } else if self.fat.follow().await.is_some() {
+            self.sector = self.bpb.sector(self.fat.current_cluster);
+        } else {
+            // broken first cluster, the dir has nothing
+            self.sector_offset = self.bpb.sector_per_cluster_usize();
+        }
     }
 
-    fn offset_index(&mut self) {
-        let spc = self.bpb.sector_per_cluster_usize();
+    /// Get next item, '.' AND '..' are skipped
+    pub(crate) async fn next_item(&mut self) -> Option<DirectoryItem> {
+        if !self.started { self.start().await; }
+        loop {
+            if self.is_end_sector() { return None; }
+            if self.index == 0 { self.update_buffer().await; }
+
+            if self.is_end() { return None; };
+
+            let special = self.is_special_item();
+            let di = DirectoryItem::from_buf(self.get_part_buf());
+            self.offset_index().await;
+            if !special { return Some(di); }
+        }
+    }
+
+    /// Go to the end of dir, where new items are written
+    pub(crate) async fn seek_end(&mut self) {
+        while self.next_item().await.is_some() {}
+    }
+
+    /// Check if the dir chain is broken, the iterator stops there
+    pub(crate) fn is_corrupted(&self) -> bool {
+        self.fat.is_corrupted()
+    }
 
+    /// Get index in the dir of the item which was returned last
+    pub(crate) fn item_index(&self) -> usize {
+        self.count - 1
+    }
+
+    fn sector_value(&self) -> u32 {
+        self.sector + self.sector_offset as u32
+    }
+
+    /// Get sectors of the cluster, or of the whole fixed root dir region
+    fn sector_count(&self) -> usize {
+        if self.fat.is_root_region() {
+            self.bpb.root_dir_sectors()
+        } else {
+            self.bpb.sector_per_cluster_usize()
+        }
+    }
+
+    async fn offset_index(&mut self) {
+        let spc = self.sector_count();
+
+        self.count += 1;
         self.index += 32;
-        if self.index % BUFFER_SIZE == 0 {
+        if self.index.is_multiple_of(BUFFER_SIZE) {
             self.sector_offset += 1;
             self.index = 0;
         }
 
-        if self.sector_offset % spc == 0
+        if self.sector_offset.is_multiple_of(spc)
             && self.sector_offset != 0 {
             if self.fat.next_is_none() {
                 self.sector_offset = spc;
             } else {
-                self.fat.next();
-                self.offset = self.bpb.offset(self.fat.current_cluster);
+                self.fat.follow().await;
+                self.sector = self.bpb.sector(self.fat.current_cluster);
                 self.sector_offset = 0;
             }
         }
     }
 
     fn is_end_sector(&self) -> bool {
-        let spc = self.bpb.sector_per_cluster_usize();
-        self.sector_offset == spc
+        self.sector_offset == self.sector_count()
+    }
+
+    /// Get count of items left from current position to the end of the fixed root dir region
+    fn free_items(&self) -> usize {
+        ((self.sector_count() - self.sector_offset) * BUFFER_SIZE - self.index) / 32
     }
 
     fn is_end(&self) -> bool {
@@ -384,87 +817,97 @@ impl<'a, T> DirIter<'a, T>
         &self.buffer[self.index..self.index + 32]
     }
 
-    fn set_deleted(&mut self) {
+    /// Move back to the item at index, so it can be updated
+    pub(crate) async fn seek(&mut self, index: usize) -> Option<DirectoryItem> {
+        while let Some(di) = self.next_item().await {
+            if self.item_index() == index {
+                self.previous().await;
+                return Some(di);
+            }
+        }
+        None
+    }
+
+    pub(crate) fn set_deleted(&mut self) {
         self.buffer[self.index] = 0xE5;
     }
 
-    pub(crate) fn update_item(&mut self, buf: &[u8]) {
+    pub(crate) async fn update_item(&mut self, buf: &[u8]) -> Result<(), DirError> {
         // append cluster if is dir end
         if self.is_end_sector() {
-            let blank_cluster = self.fat.blank_cluster();
-            self.clean_new_cluster_data(blank_cluster);
-            self.fat.write(blank_cluster, 0x0FFFFFFF);
-            self.fat.write(self.fat.current_cluster, blank_cluster);
+            if self.fat.is_root_region() { return Err(DirError::RootDirFull); }
+            // the new cluster is cleaned before the dir is linked to it
+            let (blank_cluster, _) = self.fat.allocate(0, 1, false).await
+                .map_err(DirError::from_alloc)?;
+            self.clean_new_cluster_data(blank_cluster).await;
+            self.fat.write(self.fat.current_cluster, blank_cluster).await;
             self.fat.previous();
-            self.fat.next();
-            self.fat.next();
-            self.offset = self.bpb.offset(blank_cluster);
+            self.fat.follow().await;
+            self.fat.follow().await;
+            self.sector = self.bpb.sector(blank_cluster);
             self.index = 0;
             self.sector_offset = 0;
-            self.update_buffer();
+            self.update_buffer().await;
         }
         self.buffer[self.index..self.index + 32].copy_from_slice(buf);
+        Ok(())
     }
 
-    pub(crate) fn previous(&mut self) {
+    /// Move to the slot after the updated item, write the sector before leaving it
+    async fn next_slot(&mut self) {
+        if self.index + 32 == BUFFER_SIZE { self.update().await; }
+        self.offset_index().await;
+        if self.index == 0 && !self.is_end_sector() { self.update_buffer().await; }
+    }
+
+    pub(crate) async fn previous(&mut self) {
+        self.count -= 1;
         if self.index == 0 && self.sector_offset != 0 {
             self.index = BUFFER_SIZE - 32;
             self.sector_offset -= 1;
-            self.update_buffer();
+            self.update_buffer().await;
         } else if self.index != 0 {
             self.index -= 32;
         } else {
-            let spc = self.bpb.sector_per_cluster_usize();
-            self.sector_offset = spc - 1;
+            self.sector_offset = self.sector_count() - 1;
             self.index = BUFFER_SIZE - 32;
             self.fat.previous();
-            self.update_buffer();
+            self.sector = self.bpb.sector(self.fat.current_cluster);
+            self.update_buffer().await;
         }
     }
 
-    pub(crate) fn update_buffer(&mut self) {
-        let offset = self.offset_value();
+    pub(crate) async fn update_buffer(&mut self) {
+        let sector = self.sector_value();
         self.device.read(&mut self.buffer,
-                         offset,
-                         1).unwrap();
+                         sector_address(sector),
+                         1).await.unwrap();
     }
 
-    pub(crate) fn update(&self) {
+    pub(crate) async fn update(&self) {
         self.device.write(&self.buffer,
-                          self.offset_value(),
-                          1).unwrap();
+                          sector_address(self.sector_value()),
+                          1).await.unwrap();
     }
 
-    fn clean_new_cluster_data(&self, cluster: u32) {
+    async fn clean_new_cluster_data(&self, cluster: u32) {
         let spc = self.bpb.sector_per_cluster_usize();
         for i in 0..spc {
-            let offset = self.bpb.offset(cluster) + i * BUFFER_SIZE;
+            let sector = self.bpb.sector(cluster) + i as u32;
             self.device.write(&[0; BUFFER_SIZE],
-                              offset,
-                              1).unwrap();
+                              sector_address(sector),
+                              1).await.unwrap();
         }
     }
 }
 
 /// Implement Iterator For DirIter
 impl<'a, T> Iterator for DirIter<'a, T>
-    where T: BlockDevice + Clone + Copy,
+    where T: BlockDevice,
           <T as BlockDevice>::Error: core::fmt::Debug {
     type Item = DirectoryItem;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index == 0 { self.update_buffer(); }
-
-        if self.is_end() { return None; };
-
-        if self.is_special_item() {
-            self.offset_index();
-            self.next()
-        } else {
-            let buf = self.get_part_buf();
-            let di = DirectoryItem::from_buf(buf);
-            self.offset_index();
-            Some(di)
-        }
+        block_on(self.next_item())
     }
 }
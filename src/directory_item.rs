@@ -1,5 +1,8 @@
 use core::str;
-use crate::tool::read_le_u32;
+use crate::tool::{
+    read_le_u32,
+    generate_checksum,
+};
 use crate::dir::OpType;
 
 pub(crate) enum NameType {
@@ -7,8 +10,9 @@ pub(crate) enum NameType {
     LFN,
 }
 
-#[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, PartialOrd, PartialEq)]
 pub enum ItemType {
+    #[default]
     Dir,
     File,
     LFN,
@@ -34,12 +38,6 @@ impl ItemType {
     }
 }
 
-impl Default for ItemType {
-    fn default() -> Self {
-        ItemType::Dir
-    }
-}
-
 #[derive(Default, Copy, Clone, Debug)]
 pub struct ShortDirectoryItem {
     name: [u8; 8],
@@ -58,9 +56,9 @@ impl ShortDirectoryItem {
         let mut item = [0; 32];
         let _item = [0x20; 11];
         item[0x00..0x0B].copy_from_slice(&_item);
-        item[0x00..0x00 + name.len()].copy_from_slice(name.as_bytes());
+        item[0x00..name.len()].copy_from_slice(name.as_bytes());
         item[0x08..0x08 + extension.len()].copy_from_slice(extension.as_bytes());
-        item[0x00..0x00 + name.len()].make_ascii_uppercase();
+        item[0x00..name.len()].make_ascii_uppercase();
         item[0x08..0x08 + extension.len()].make_ascii_uppercase();
 
         let mut cluster: [u8; 4] = cluster.to_be_bytes();
@@ -243,7 +241,7 @@ impl LongDirectoryItem {
         }
     }
 
-    fn to_utf8(&self) -> ([u8; 13 * 3], usize) {
+    fn to_utf8(self) -> ([u8; 13 * 3], usize) {
         let (mut utf8, mut len) = ([0; 13 * 3], 0);
 
         let mut op = |part: &[u8]| {
@@ -254,7 +252,7 @@ impl LongDirectoryItem {
                 if unicode <= 0x007F {
                     utf8[len] = unicode as u8;
                     len += 1;
-                } else if unicode >= 0x0080 && unicode <= 0x07FF {
+                } else if (0x0080..=0x07FF).contains(&unicode) {
                     let part1 = (0b11000000 | (0b00011111 & (unicode >> 6))) as u8;
                     let part2 = (0b10000000 | (0b00111111) & unicode) as u8;
 
@@ -315,50 +313,46 @@ impl DirectoryItem {
     }
 
     fn get_sfn(&self) -> Option<([u8; 12], usize)> {
-        if self.sfn.is_some() {
-            Some(self.sfn.as_ref().unwrap().get_full_name_bytes())
-        } else {
-            None
-        }
+        self.sfn.as_ref().map(|sfn| sfn.get_full_name_bytes())
     }
 
     fn get_lfn(&self) -> Option<([u8; 13 * 3], usize)> {
-        if self.lfn.is_some() {
-            Some(self.lfn.as_ref().unwrap().to_utf8())
-        } else {
-            None
-        }
+        self.lfn.map(|lfn| lfn.to_utf8())
+    }
+
+    pub(crate) fn check_sum(&self) -> Option<u8> {
+        self.lfn.as_ref().map(|lfn| lfn.check_sum)
+    }
+
+    pub(crate) fn sfn_check_sum(&self) -> Option<u8> {
+        self.sfn_name().map(|name| generate_checksum(&name))
+    }
+
+    pub(crate) fn sfn_name(&self) -> Option<[u8; 11]> {
+        self.sfn.as_ref().map(|sfn| {
+            let mut name = [0; 11];
+            name[0x00..0x08].copy_from_slice(&sfn.name);
+            name[0x08..0x0B].copy_from_slice(&sfn.extension);
+            name
+        })
     }
 
     pub(crate) fn count_of_name(&self) -> Option<usize> {
-        if self.lfn.is_some() {
-            Some(self.lfn.as_ref().unwrap().count_of_name())
-        } else {
-            None
-        }
+        self.lfn.as_ref().map(|lfn| lfn.count_of_name())
     }
 
     pub(crate) fn is_name_end(&self) -> Option<bool> {
-        if self.lfn.is_some() {
-            Some(self.lfn.as_ref().unwrap().is_name_end())
-        } else {
-            None
-        }
+        self.lfn.as_ref().map(|lfn| lfn.is_name_end())
     }
 
     pub(crate) fn length(&self) -> Option<usize> {
-        if self.sfn.is_some() {
-            Some(self.sfn.as_ref().unwrap().length as usize)
-        } else {
-            None
-        }
+        self.sfn.as_ref().map(|sfn| sfn.length as usize)
     }
 
     pub(crate) fn bytes(&self) -> [u8; 32] {
-        if self.sfn.is_some() {
-            self.sfn.as_ref().unwrap().bytes(self.item_type)
-        } else {
-            self.lfn.as_ref().unwrap().bytes()
+        match self.sfn.as_ref() {
+            Some(sfn) => sfn.bytes(self.item_type),
+            None => self.lfn.as_ref().unwrap().bytes(),
         }
     }
 
@@ -446,6 +440,10 @@ impl DirectoryItem {
         self.sfn.as_mut().unwrap().length = length as u32;
     }
 
+    pub(crate) fn set_cluster(&mut self, cluster: u32) {
+        self.sfn.as_mut().unwrap().cluster = cluster;
+    }
+
     pub(crate) fn is_lfn(&self) -> bool {
         ItemType::LFN == self.item_type
     }
@@ -1,80 +1,296 @@
+use core::cell::Cell;
 use block_device::BlockDevice;
+use crate::async_device::{
+    AsyncBlockDevice,
+    block_on,
+};
 use crate::BUFFER_SIZE;
-use crate::tool::read_le_u32;
+use crate::bpb::{
+    BIOSParameterBlock,
+    FatType,
+};
+use crate::tool::{
+    read_le_u16,
+    read_le_u32,
+    sector_address,
+};
 
-#[derive(Debug, Copy, Clone)]
-pub struct FAT<T>
-    where T: BlockDevice + Clone + Copy,
-          <T as BlockDevice>::Error: core::fmt::Debug {
-    device: T,
-    fat_offset: usize,
+/// Define AllocError
+#[derive(Debug, PartialOrd, PartialEq)]
+pub(crate) enum AllocError {
+    VolumeFull,
+    NotContiguous,
+}
+
+#[derive(Debug)]
+pub struct FAT<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    device: &'a T,
+    bitmap: Option<&'a [Cell<u8>]>,
+    fat_sector: u32,
+    sector_per_fat: u32,
+    num_fat: u32,
+    fat_type: FatType,
+    max_cluster: u32,
     start_cluster: u32,
     previous_cluster: u32,
     pub(crate) current_cluster: u32,
     next_cluster: Option<u32>,
+    followed: u32,
+    corrupted: bool,
     buffer: [u8; BUFFER_SIZE],
+    buffer_block: Option<usize>,
 }
 
-impl<T> FAT<T>
-    where T: BlockDevice + Clone + Copy,
-          <T as BlockDevice>::Error: core::fmt::Debug {
-    pub(crate) fn new(cluster: u32, device: T, fat_offset: usize) -> Self {
+/// Copy Of FAT Borrows The Same Device, Device Itself Needn't Be Copy
+impl<'a, T> Clone for FAT<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for FAT<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {}
+
+impl<'a, T> FAT<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    pub(crate) fn new(cluster: u32, device: &'a T, bpb: &BIOSParameterBlock, bitmap: Option<&'a [Cell<u8>]>) -> Self {
         Self {
             device,
-            fat_offset,
+            bitmap,
+            fat_sector: bpb.fat1(),
+            sector_per_fat: bpb.sector_per_fat,
+            num_fat: bpb.num_fat as u32,
+            fat_type: bpb.fat_type,
+            max_cluster: bpb.max_cluster(),
             start_cluster: cluster,
             previous_cluster: 0,
             current_cluster: 0,
             next_cluster: None,
+            followed: 0,
+            corrupted: false,
             buffer: [0; BUFFER_SIZE],
+            buffer_block: None,
+        }
+    }
+
+    /// Get FAT of another chain, it shares the bitmap
+    pub(crate) fn with_start(&self, cluster: u32) -> Self {
+        Self {
+            start_cluster: cluster,
+            previous_cluster: 0,
+            current_cluster: 0,
+            next_cluster: None,
+            followed: 0,
+            corrupted: false,
+            buffer_block: None,
+            ..*self
         }
     }
 
-    pub(crate) fn blank_cluster(&mut self) -> u32 {
-        let mut cluster = 0;
-        let mut done = false;
+    /// Allocate count clusters AND link them after last, last is 0 for new chain
+    ///
+    /// Clusters come in as few runs as possible, the one right after last first.
+    /// Return first cluster AND whether it is contiguous with last
+    pub(crate) async fn allocate(&mut self, last: u32, count: u32, contiguous: bool) -> Result<(u32, bool), AllocError> {
+        self.buffer_block = None;
+        if self.free_count(count).await < count { return Err(AllocError::VolumeFull); }
+        if contiguous {
+            let has_run = if last != 0 {
+                self.free_run(last + 1, count).await == count
+            } else {
+                self.longest_free_run(count).await.map(|(_, run)| run) == Some(count)
+            };
+            if !has_run { return Err(AllocError::NotContiguous); }
+        }
 
-        for block in 0.. {
-            self.device.read(&mut self.buffer,
-                             self.fat_offset + block * BUFFER_SIZE,
-                             1).unwrap();
-            for i in (0..BUFFER_SIZE).step_by(4) {
-                if read_le_u32(&self.buffer[i..i + 4]) == 0 {
-                    done = true;
-                    break;
-                } else { cluster += 1; }
+        let mut previous = last;
+        let mut first = 0;
+        let mut is_contiguous = true;
+        let mut left = count;
+        while left > 0 {
+            let after = if previous != 0 { self.free_run(previous + 1, left).await } else { 0 };
+            let (start, run) = if after != 0 {
+                (previous + 1, after)
+            } else {
+                self.longest_free_run(left).await.unwrap()
+            };
+
+            let end = start + run - 1;
+            for cluster in start..end {
+                self.write(cluster, cluster + 1).await;
+            }
+            self.write(end, 0x0FFFFFFF).await;
+
+            if previous != 0 {
+                self.write(previous, start).await;
+                is_contiguous &= start == previous + 1;
+            }
+            if first == 0 { first = start; }
+            previous = end;
+            left -= run;
+        }
+
+        Ok((first, is_contiguous))
+    }
+
+    /// Check if the cluster is free, bitmap is used if there is one
+    async fn is_free(&mut self, cluster: u32) -> bool {
+        match self.bitmap {
+            Some(bitmap) => bitmap[cluster as usize / 8].get() & (1 << (cluster % 8)) == 0,
+            None => self.get(cluster).await == 0,
+        }
+    }
+
+    /// Count free clusters, stop at max
+    async fn free_count(&mut self, max: u32) -> u32 {
+        let mut count = 0;
+        let mut cluster = 2;
+        while count < max && cluster <= self.max_cluster {
+            if self.is_free(cluster).await { count += 1; }
+            cluster += 1;
+        }
+        count
+    }
+
+    /// Get count of free clusters in a row from cluster, no more than max
+    async fn free_run(&mut self, cluster: u32, max: u32) -> u32 {
+        let mut count = 0;
+        while count < max && cluster + count <= self.max_cluster && self.is_free(cluster + count).await {
+            count += 1;
+        }
+        count
+    }
+
+    /// Find the first free run which has count clusters, OR the longest one
+    async fn longest_free_run(&mut self, count: u32) -> Option<(u32, u32)> {
+        let mut longest = None;
+        let mut longest_run = 0;
+        let mut cluster = 2;
+        while cluster <= self.max_cluster {
+            let run = self.free_run(cluster, count).await;
+            if run == count { return Some((cluster, run)); }
+            if run > longest_run {
+                longest = Some((cluster, run));
+                longest_run = run;
+            }
+            cluster += run + 1;
+        }
+        longest
+    }
+
+    pub(crate) async fn write(&mut self, cluster: u32, value: u32) {
+        self.set_entry(cluster, value).await.unwrap();
+    }
+
+    /// Set the FAT entry value of the cluster, FAT copies too
+    async fn set_entry(&mut self, cluster: u32, value: u32) -> Result<(), T::Error> {
+        if let Some(byte) = self.bitmap.and_then(|bitmap| bitmap.get(cluster as usize / 8)) {
+            let bit = 1 << (cluster % 8);
+            byte.set(if value == 0 { byte.get() & !bit } else { byte.get() | bit });
+        }
+
+        self.buffer_block = None;
+        match self.fat_type {
+            FatType::FAT32 => {
+                let offset = (cluster as usize) * 4;
+                self.load(offset / BUFFER_SIZE).await?;
+                let offset_left = offset % BUFFER_SIZE;
+                // the high 4 bits are reserved, keep them
+                let old = read_le_u32(&self.buffer[offset_left..offset_left + 4]);
+                let value = (value & 0x0FFFFFFF) | (old & 0xF0000000);
+                self.buffer[offset_left..offset_left + 4]
+                    .copy_from_slice(&value.to_le_bytes());
+                self.store().await
+            }
+            FatType::FAT16 => {
+                let offset = (cluster as usize) * 2;
+                self.load(offset / BUFFER_SIZE).await?;
+                let offset_left = offset % BUFFER_SIZE;
+                self.buffer[offset_left..offset_left + 2]
+                    .copy_from_slice(&(value as u16).to_le_bytes());
+                self.store().await
             }
-            if done { break; }
+            FatType::FAT12 => {
+                let offset = (cluster as usize) * 3 / 2;
+                let value = (value & 0xFFF) as u16;
+                let (low, high) = if cluster & 1 == 1 {
+                    let low = (self.get_byte(offset).await? & 0x0F) | ((value << 4) as u8);
+                    (low, (value >> 4) as u8)
+                } else {
+                    let high = (self.get_byte(offset + 1).await? & 0xF0) | ((value >> 8) as u8);
+                    (value as u8, high)
+                };
+
+                let offset_left = offset % BUFFER_SIZE;
+                if offset_left + 1 < BUFFER_SIZE {
+                    // both bytes go in one sector write, a power loss can't leave half entry
+                    self.load(offset / BUFFER_SIZE).await?;
+                    self.buffer[offset_left] = low;
+                    self.buffer[offset_left + 1] = high;
+                    self.store().await
+                } else {
+                    // 12 bits entry crosses the sector boundary, write byte by byte
+                    self.set_byte(offset, low).await?;
+                    self.set_byte(offset + 1, high).await
+                }
+            }
+        }
+    }
+
+    /// Free the whole chain which starts from cluster
+    pub(crate) async fn free_chain(&mut self, cluster: u32) {
+        // another FAT may have changed the chain since the buffer was read
+        self.buffer_block = None;
+        let mut cluster = cluster;
+        for _ in 0..self.max_cluster {
+            if cluster < 2 || cluster > self.max_cluster { break; }
+            let next = self.get(cluster).await;
+            self.write(cluster, 0).await;
+            if self.fat_type.is_end(next) { break; }
+            cluster = next;
         }
-        cluster
     }
 
-    pub(crate) fn write(&mut self, cluster: u32, value: u32) {
-        let offset = (cluster as usize) * 4;
-        let block_offset = offset / BUFFER_SIZE;
-        let offset_left = offset % BUFFER_SIZE;
-        let offset = self.fat_offset + block_offset * BUFFER_SIZE;
-        let mut value: [u8; 4] = value.to_be_bytes();
-        value.reverse();
+    /// Check the clean shutdown bit of FAT[1], FAT12 is always clean
+    pub(crate) async fn is_clean(&mut self) -> Result<bool, T::Error> {
+        match self.fat_type.clean_mask() {
+            Some(mask) => {
+                self.buffer_block = None;
+                Ok(self.entry(1).await? & mask != 0)
+            }
+            None => Ok(true),
+        }
+    }
 
-        self.device.read(&mut self.buffer,
-                         offset,
-                         1).unwrap();
-        self.buffer[offset_left..offset_left + 4].copy_from_slice(&value);
-        self.device.write(&self.buffer,
-                          offset,
-                          1).unwrap();
+    /// Set OR clear the clean shutdown bit of FAT[1], it is written only if it changes
+    pub(crate) async fn set_clean(&mut self, clean: bool) -> Result<(), T::Error> {
+        if let Some(mask) = self.fat_type.clean_mask() {
+            self.buffer_block = None;
+            let value = self.entry(1).await?;
+            let new_value = if clean { value | mask } else { value & !mask };
+            if new_value != value { self.set_entry(1, new_value).await?; }
+        }
+        Ok(())
     }
 
-    pub(crate) fn refresh(&mut self, start_cluster: u32) {
-        self.current_cluster = 0;
-        self.start_cluster = start_cluster;
+    /// Write FAT1 block to every FAT copy
+    pub(crate) async fn sync_copies(&mut self, block: usize) {
+        self.buffer_block = None;
+        self.load(block).await.unwrap();
+        self.store().await.unwrap();
     }
 
     pub(crate) fn previous(&mut self) {
         if self.current_cluster != 0 {
             self.next_cluster = Some(self.current_cluster);
             self.current_cluster = self.previous_cluster;
+            self.followed -= 1;
         }
     }
 
@@ -82,42 +298,132 @@ impl<T> FAT<T>
         self.next_cluster.is_none()
     }
 
-    fn current_cluster_usize(&self) -> usize {
-        self.current_cluster as usize
+    /// Check if the chain ran into a bad OR out of range cluster, OR loops
+    pub(crate) fn is_corrupted(&self) -> bool {
+        self.corrupted
     }
-}
 
-impl<T> Iterator for FAT<T>
-    where T: BlockDevice + Clone + Copy,
-          <T as BlockDevice>::Error: core::fmt::Debug {
-    type Item = Self;
+    fn is_valid(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster <= self.max_cluster
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    /// The fixed root dir region of FAT12/FAT16 is not a cluster chain
+    pub(crate) fn is_root_region(&self) -> bool {
+        self.start_cluster == 0
+    }
+
+    /// Get the FAT entry value of the cluster, FAT32 entry is 28 bits
+    pub(crate) async fn get(&mut self, cluster: u32) -> u32 {
+        self.entry(cluster).await.unwrap()
+    }
+
+    /// Get the FAT entry value of the cluster, error of device is returned
+    async fn entry(&mut self, cluster: u32) -> Result<u32, T::Error> {
+        Ok(match self.fat_type {
+            FatType::FAT32 => {
+                let offset = (cluster as usize) * 4;
+                self.load(offset / BUFFER_SIZE).await?;
+                let offset_left = offset % BUFFER_SIZE;
+                read_le_u32(&self.buffer[offset_left..offset_left + 4]) & 0x0FFFFFFF
+            }
+            FatType::FAT16 => {
+                let offset = (cluster as usize) * 2;
+                self.load(offset / BUFFER_SIZE).await?;
+                let offset_left = offset % BUFFER_SIZE;
+                read_le_u16(&self.buffer[offset_left..offset_left + 2]) as u32
+            }
+            FatType::FAT12 => {
+                let offset = (cluster as usize) * 3 / 2;
+                let value = (self.get_byte(offset).await? as u16)
+                    | ((self.get_byte(offset + 1).await? as u16) << 8);
+                if cluster & 1 == 1 {
+                    (value >> 4) as u32
+                } else {
+                    (value & 0xFFF) as u32
+                }
+            }
+        })
+    }
+
+    async fn get_byte(&mut self, offset: usize) -> Result<u8, T::Error> {
+        self.load(offset / BUFFER_SIZE).await?;
+        Ok(self.buffer[offset % BUFFER_SIZE])
+    }
+
+    async fn set_byte(&mut self, offset: usize, value: u8) -> Result<(), T::Error> {
+        self.load(offset / BUFFER_SIZE).await?;
+        self.buffer[offset % BUFFER_SIZE] = value;
+        self.store().await
+    }
+
+    /// Read the FAT block into buffer, if it is not there yet
+    async fn load(&mut self, block: usize) -> Result<(), T::Error> {
+        if self.buffer_block != Some(block) {
+            // buffer is half read if it fails
+            self.buffer_block = None;
+            let sector = self.fat_sector + block as u32;
+            self.device.read(&mut self.buffer,
+                             sector_address(sector),
+                             1).await?;
+            self.buffer_block = Some(block);
+        }
+        Ok(())
+    }
+
+    /// Write the buffer back to the FAT block it was read from, FAT copies too
+    async fn store(&mut self) -> Result<(), T::Error> {
+        let block = self.buffer_block.unwrap();
+        for fat in 0..self.num_fat {
+            let sector = self.fat_sector + fat * self.sector_per_fat + block as u32;
+            self.device.write(&self.buffer,
+                              sector_address(sector),
+                              1).await?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T> FAT<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    /// Follow the chain to next cluster, return copy of FAT which is at it
+    pub(crate) async fn follow(&mut self) -> Option<Self> {
         if self.current_cluster == 0 {
+            // the chain which starts from 0 is empty
+            if self.start_cluster == 0 { return None; }
+            if !self.is_valid(self.start_cluster) {
+                self.corrupted = true;
+                return None;
+            }
             self.current_cluster = self.start_cluster;
         } else {
-            let next_cluster = self.next_cluster;
-            if next_cluster.is_some() {
-                self.previous_cluster = self.current_cluster;
-                self.current_cluster = next_cluster.unwrap();
-            } else {
-                return None;
+            match self.next_cluster {
+                Some(next_cluster) => {
+                    self.previous_cluster = self.current_cluster;
+                    self.current_cluster = next_cluster;
+                }
+                None => return None,
             }
         }
 
-        let offset = self.current_cluster_usize() * 4;
-        let block_offset = offset / BUFFER_SIZE;
-        let offset_left = offset % BUFFER_SIZE;
-
-        self.device.read(&mut self.buffer,
-                         self.fat_offset + block_offset * BUFFER_SIZE,
-                         1).unwrap();
+        // chain longer than count of clusters must be a loop
+        self.followed += 1;
+        if self.followed > self.max_cluster - 1 {
+            self.corrupted = true;
+            self.next_cluster = None;
+            return None;
+        }
 
-        let next_cluster = read_le_u32(&self.buffer[offset_left..offset_left + 4]);
-        let next_cluster = if next_cluster == 0x0FFFFFFF {
+        self.buffer_block = None;
+        let next_cluster = self.get(self.current_cluster).await;
+        let next_cluster = if self.fat_type.is_end(next_cluster) {
             None
-        } else {
+        } else if self.is_valid(next_cluster) {
             Some(next_cluster)
+        } else {
+            // free, bad OR out of range cluster can't be in chain
+            self.corrupted = true;
+            None
         };
 
         self.next_cluster = next_cluster;
@@ -127,4 +433,29 @@ impl<T> Iterator for FAT<T>
             ..(*self)
         })
     }
-}
\ No newline at end of file
+
+    /// Follow the chain to the end, return count of clusters followed
+    pub(crate) async fn follow_to_end(&mut self) -> usize {
+        let mut count = 0;
+        while self.follow().await.is_some() { count += 1; }
+        count
+    }
+
+    /// Follow the chain n clusters AND one more, like Iterator::nth
+    pub(crate) async fn follow_nth(&mut self, n: usize) -> Option<Self> {
+        for _ in 0..n {
+            self.follow().await?;
+        }
+        self.follow().await
+    }
+}
+
+impl<'a, T> Iterator for FAT<'a, T>
+    where T: BlockDevice,
+          <T as BlockDevice>::Error: core::fmt::Debug {
+    type Item = Self;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        block_on(self.follow())
+    }
+}
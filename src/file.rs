@@ -1,17 +1,53 @@
 use core::cmp;
+use core::cell::Cell;
 use block_device::BlockDevice;
+use crate::async_device::{
+    AsyncBlockDevice,
+    block_on,
+};
 use crate::bpb::BIOSParameterBlock;
 use crate::directory_item::DirectoryItem;
-use crate::fat::FAT;
+use crate::fat::{
+    AllocError,
+    FAT,
+};
 use crate::BUFFER_SIZE;
-use crate::dir::DirIter;
-use crate::tool::get_needed_sector;
+use crate::dir::{
+    Dir,
+    DirError,
+    DirIter,
+};
+use crate::volume::Volume;
+use crate::open_file::OpenFile;
+use crate::handle::{
+    FileHandle,
+    Position,
+};
+use crate::tool::{
+    get_needed_sector,
+    sector_address,
+};
 
 /// Define FileError
-#[derive(Debug)]
+#[derive(Debug, PartialOrd, PartialEq)]
 pub enum FileError {
     BufTooSmall,
     WriteError,
+    ReadOnly,
+    Corrupted,
+    VolumeFull,
+    NotContiguous,
+    NotReadable,
+    NotWritable,
+}
+
+impl FileError {
+    fn from_alloc(error: AllocError) -> FileError {
+        match error {
+            AllocError::VolumeFull => FileError::VolumeFull,
+            AllocError::NotContiguous => FileError::NotContiguous,
+        }
+    }
 }
 
 /// Define WriteType
@@ -20,120 +56,326 @@ pub enum WriteType {
     Append,
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Options Of Opening File, Like std::fs::OpenOptions, Every Option Is false By Default
+///
+/// open() scans the dir once, the file is created there if it is not found
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct OpenOptions {
+    pub(crate) read: bool,
+    pub(crate) write: bool,
+    pub(crate) append: bool,
+    pub(crate) truncate: bool,
+    pub(crate) create: bool,
+    pub(crate) create_new: bool,
+}
+
+/// File Borrows The Volume, Clone Of It Shares The Slot In Open File Table
+#[derive(Debug)]
 pub struct File<'a, T>
-    where T: BlockDevice + Clone + Copy,
-          <T as BlockDevice>::Error: core::fmt::Debug {
-    pub(crate) device: T,
-    pub(crate) bpb: &'a BIOSParameterBlock,
-    pub(crate) dir_cluster: u32,
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    pub(crate) volume: &'a Volume<'a, T>,
+    pub(crate) position: Position,
     pub(crate) detail: DirectoryItem,
-    pub(crate) fat: FAT<T>,
+    pub(crate) fat: FAT<'a, T>,
+    pub(crate) slot: Option<&'a Cell<OpenFile>>,
+    pub(crate) options: OpenOptions,
+}
+
+impl<'a, T> Clone for File<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    fn clone(&self) -> Self {
+        if let Some(slot) = self.slot {
+            let mut open = slot.get();
+            open.count += 1;
+            slot.set(open);
+        }
+        File {
+            volume: self.volume,
+            position: self.position,
+            detail: self.detail,
+            fat: self.fat,
+            slot: self.slot,
+            options: self.options,
+        }
+    }
+}
+
+/// The Last File Of The Position Frees Its Slot In Open File Table
+impl<'a, T> Drop for File<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            let mut open = slot.get();
+            open.count -= 1;
+            if open.count == 0 { open.position = None; }
+            slot.set(open);
+        }
+    }
 }
 
 /// To Read File Per Sector By Iterator
 pub struct ReadIter<'a, T>
-    where T: BlockDevice + Clone + Copy,
+    where T: BlockDevice,
           <T as BlockDevice>::Error: core::fmt::Debug {
-    device: T,
+    device: &'a T,
     buffer: [u8; BUFFER_SIZE],
     bpb: &'a BIOSParameterBlock,
-    fat: FAT<T>,
+    fat: FAT<'a, T>,
     left_length: usize,
     read_count: usize,
     need_count: usize,
 }
 
+/// To Get Runs Of Consecutive Sectors Of File By Iterator, Item Is (start_sector, sector_count)
+pub struct Extents<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    bpb: &'a BIOSParameterBlock,
+    fat: FAT<'a, T>,
+    left_sector: usize,
+    next_sector: Option<u32>,
+    /// sectors of the first cluster which are skipped
+    skip: usize,
+}
+
+/// Runs Of Extents Cut To blocks_per_transfer, Item Is (start_sector, sector_count, sectors_before)
+struct Transfers<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    extents: Extents<'a, T>,
+    limit: usize,
+    run: Option<(u32, usize)>,
+    sectors: usize,
+    done: usize,
+}
+
 impl<'a, T> File<'a, T>
-    where T: BlockDevice + Clone + Copy,
+    where T: BlockDevice,
           <T as BlockDevice>::Error: core::fmt::Debug {
     /// Read File To Buffer, Return File Length
+    ///
+    /// The whole chain is followed like write() does, Corrupted if it is broken OR loops anywhere
     pub fn read(&self, buf: &mut [u8]) -> Result<usize, FileError> {
-        let length = self.detail.length().unwrap();
-        let spc = self.bpb.sector_per_cluster_usize();
-        let cluster_size = spc * BUFFER_SIZE;
-        let mut number_of_blocks = spc;
+        block_on(self.read_async(buf))
+    }
 
+    /// Write Data To File, Using Append OR OverWritten
+    ///
+    /// OverWritten writes data to a new chain, points the item to it AND frees the old chain.
+    /// Cluster AND length are in the same item, the switch is one sector write.
+    /// If it stops before the switch, the file keeps old data AND the new chain is lost.
+    /// If it stops after the switch, the file has new data AND the old chain is lost.
+    /// Free clusters for the new data are needed while the old ones are still used.
+    ///
+    /// Append writes data after the end of file, then updates the length.
+    /// If it stops before the update, the file keeps old length, clusters past it stay reserved.
+    ///
+    /// With Volume::cached, the writes reach the device later but in the same order,
+    /// so the above holds for what is on the device. Call sync() to have the new data there
+    pub fn write(&mut self, buf: &[u8], write_type: WriteType) -> Result<(), FileError> {
+        block_on(self.write_async(buf, write_type))
+    }
+
+    /// Reserve Clusters For length Bytes, Return Whether The Chain Is Contiguous
+    ///
+    /// File length doesn't change, Append writes into the reserved clusters.
+    /// Checker reports clusters past the length as ExtraClusters, OverWritten frees them
+    pub fn reserve(&mut self, length: usize, contiguous: bool) -> Result<bool, FileError> {
+        block_on(self.reserve_clusters(length, contiguous))
+    }
+
+    /// Read Per Sector, Return ReadIter
+    pub fn read_per_sector(&self) -> Result<ReadIter<'_, T>, FileError> {
+        if !self.options.read { return Err(FileError::NotReadable); }
+        let left_length = self.length();
+        Ok(ReadIter::<T> {
+            device: &self.volume.device,
+            buffer: [0; BUFFER_SIZE],
+            bpb: &self.volume.bpb,
+            fat: self.chain(),
+            left_length,
+            read_count: 0,
+            need_count: get_needed_sector(left_length),
+        })
+    }
+
+    /// Get Runs Of Consecutive Sectors Which Hold The File, Return Extents
+    ///
+    /// Only FAT is read. If the chain is broken, Extents stops early AND is_corrupted() is true.
+    /// File needs no read option, the place of a write-only file is needed to DMA into it
+    pub fn extents(&self) -> Extents<'a, T> {
+        Extents::new(&self.volume.bpb, self.chain(), 0, get_needed_sector(self.length()))
+    }
+
+    /// Get Sector Which Holds The Byte At offset, Only FAT Is Read
+    ///
+    /// Return None if offset is not less than file length, OR the chain is broken
+    pub fn offset_sector(&self, offset: usize) -> Option<u32> {
+        if offset >= self.length() { return None; }
+        let cluster_size = self.volume.bpb.sector_per_cluster_usize() * BUFFER_SIZE;
+        let mut fat = self.chain();
+        let f = fat.nth(offset / cluster_size)?;
+        Some(self.volume.bpb.sector(f.current_cluster) + (offset % cluster_size / BUFFER_SIZE) as u32)
+    }
+}
+
+impl<'a, T> File<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    /// Read File To Buffer, Async Version Of read()
+    pub async fn read_async(&self, buf: &mut [u8]) -> Result<usize, FileError> {
+        if !self.options.read { return Err(FileError::NotReadable); }
+        let length = self.length();
         if buf.len() < length { return Err(FileError::BufTooSmall); }
 
-        let mut index = 0;
-        self.fat.map(|f| {
-            let offset = self.bpb.offset(f.current_cluster);
-            let end = if (length - index) < cluster_size {
-                let bytes_left = length % cluster_size;
-                number_of_blocks = get_needed_sector(bytes_left);
-                index + bytes_left
-            } else {
-                index + cluster_size
-            };
-            self.device.read(&mut buf[index..end],
-                             offset,
-                             number_of_blocks).unwrap();
-            index += cluster_size;
-        }).last();
+        // file length caps sectors to read
+        let mut transfers = self.transfers(&self.chain(), 0, get_needed_sector(length));
+        while let Some((sector, count, done)) = transfers.next_transfer().await {
+            let index = done * BUFFER_SIZE;
+            // the last sector may be part of a sector, read it through buf_read
+            let full = cmp::min(count, (length - index) / BUFFER_SIZE);
+            if full != 0 {
+                self.volume.device.read(&mut buf[index..index + full * BUFFER_SIZE],
+                                        sector_address(sector),
+                                        full).await.unwrap();
+            }
+            if full < count {
+                let mut buf_read = [0; BUFFER_SIZE];
+                self.volume.device.read(&mut buf_read,
+                                        sector_address(sector + full as u32),
+                                        1).await.unwrap();
+                let index = index + full * BUFFER_SIZE;
+                buf[index..length].copy_from_slice(&buf_read[0..length - index]);
+            }
+        }
 
+        if !transfers.is_complete() || !transfers.is_rest_valid().await { return Err(FileError::Corrupted); }
         Ok(length)
     }
 
-    /// Write Data To File, Using Append OR OverWritten
-    pub fn write(&mut self, buf: &[u8], write_type: WriteType) -> Result<(), FileError> {
+    /// Write Data To File, Async Version Of write()
+    pub async fn write_async(&mut self, buf: &[u8], write_type: WriteType) -> Result<(), FileError> {
+        if self.volume.is_read_only() { return Err(FileError::ReadOnly); }
+        if !self.options.is_writable() { return Err(FileError::NotWritable); }
+        // file opened for append writes after the end of file only
+        let write_type = if self.options.append { WriteType::Append } else { write_type };
+        // walk the chain first, nothing is written to broken chain
+        let mut fat = self.chain();
+        fat.follow_to_end().await;
+        if fat.is_corrupted() || self.has_no_chain() { return Err(FileError::Corrupted); }
+        // empty file without cluster gets a new chain, Append is the same as OverWritten
+        let write_type = if self.cluster() == 0 { WriteType::OverWritten } else { write_type };
+        self.volume.mark_dirty().await;
+
         let num_cluster = match write_type {
             WriteType::OverWritten => self.num_cluster(buf.len()),
-            WriteType::Append => self.num_cluster(buf.len() + self.detail.length().unwrap())
+            WriteType::Append => self.num_cluster(buf.len() + self.length())
         };
 
         match write_type {
             WriteType::OverWritten => {
-                self.fat.map(|mut f| f.write(f.current_cluster, 0)).last();
-                self.write_blank_fat(num_cluster);
-                self._write(buf, &self.fat);
+                // old chain is used until the item points to the new one
+                let (first, _) = self.fat.allocate(0, cmp::max(num_cluster, 1) as u32, false).await
+                    .map_err(FileError::from_alloc)?;
+                let fat = self.fat.with_start(first);
+                self._write(buf, &fat, 0).await;
+
+                let old = self.cluster();
+                self.update_item(first, buf.len()).await;
+                self.fat = fat;
+                self.fat.free_chain(old).await;
             }
             WriteType::Append => {
-                let mut fat = self.fat;
-                let exist_fat = fat.count();
-                fat.find(|_| false);
-
-                let (new_cluster, index) = self.fill_left_sector(buf, fat.current_cluster);
-                if new_cluster {
-                    let buf = &buf[index..];
-                    let bl = self.fat.blank_cluster();
+                let spc = self.volume.bpb.sector_per_cluster_usize();
+                let cluster_size = spc * BUFFER_SIZE;
+                let mut fat = self.chain();
+                let exist_fat = fat.follow_to_end().await;
+                if num_cluster > exist_fat {
+                    self.fat.allocate(fat.current_cluster, (num_cluster - exist_fat) as u32, false).await
+                        .map_err(FileError::from_alloc)?;
+                }
 
-                    fat.write(fat.current_cluster, bl);
-                    self.write_blank_fat(num_cluster - exist_fat);
-                    fat.refresh(bl);
+                // the cluster which has the end of file, reserved clusters may follow it
+                let mut fat = self.chain();
+                let length = self.length();
+                if fat.follow_nth(length.saturating_sub(1) / cluster_size).await.is_none() {
+                    return Err(FileError::Corrupted);
+                }
 
-                    self._write(buf, &fat);
+                let index = self.fill_left_sector(buf, fat.current_cluster).await;
+                if index < buf.len() {
+                    // the rest starts at a sector boundary, in this cluster OR the next one
+                    let used_sector = (length + index) / BUFFER_SIZE - length.saturating_sub(1) / cluster_size * spc;
+                    let (start, skip) = if used_sector == spc {
+                        (fat.get(fat.current_cluster).await, 0)
+                    } else {
+                        (fat.current_cluster, used_sector)
+                    };
+                    self._write(&buf[index..], &fat.with_start(start), skip).await;
                 }
+                self.update_item(self.cluster(), buf.len() + length).await;
             }
         }
 
-        match write_type {
-            WriteType::OverWritten => self.update_length(buf.len()),
-            WriteType::Append => self.update_length(buf.len() + self.detail.length().unwrap())
-        };
-
         Ok(())
     }
 
-    /// Read Per Sector, Return ReadIter
-    pub fn read_per_sector(&self) -> ReadIter<T> {
-        let left_length = self.detail.length().unwrap();
-        ReadIter::<T> {
-            device: self.device,
-            buffer: [0; BUFFER_SIZE],
-            bpb: self.bpb,
-            fat: self.fat,
-            left_length,
-            read_count: 0,
-            need_count: get_needed_sector(left_length),
+    /// Reserve Clusters For length Bytes, Async Version Of reserve()
+    pub async fn reserve_async(&mut self, length: usize, contiguous: bool) -> Result<bool, FileError> {
+        self.reserve_clusters(length, contiguous).await
+    }
+
+    /// Write Back Cached Sectors, So Data AND Length Of The File Are On The Device
+    ///
+    /// Length is updated by every write, only cached sectors are pending.
+    /// The volume stays dirty, Volume::flush() marks it clean
+    pub fn sync(&self) -> Result<(), <T as AsyncBlockDevice>::Error> {
+        self.volume.write_back()
+    }
+
+    /// Get Handle Which Borrows Nothing, See Volume::file()
+    pub fn handle(&self) -> FileHandle {
+        FileHandle {
+            id: self.volume.bpb.id,
+            position: self.position,
+        }
+    }
+
+    async fn reserve_clusters(&mut self, length: usize, contiguous: bool) -> Result<bool, FileError> {
+        if self.volume.is_read_only() { return Err(FileError::ReadOnly); }
+        if !self.options.is_writable() { return Err(FileError::NotWritable); }
+
+        let mut fat = self.chain();
+        let mut exist_fat = 0;
+        let mut is_contiguous = true;
+        let mut last = 0;
+        while let Some(f) = fat.follow().await {
+            if last != 0 && f.current_cluster != last + 1 { is_contiguous = false; }
+            last = f.current_cluster;
+            exist_fat += 1;
         }
+        if fat.is_corrupted() || self.has_no_chain() { return Err(FileError::Corrupted); }
+        if contiguous && !is_contiguous { return Err(FileError::NotContiguous); }
+
+        let num_cluster = self.num_cluster(length);
+        if num_cluster <= exist_fat { return Ok(is_contiguous); }
+        self.volume.mark_dirty().await;
+
+        let (first, new_contiguous) = self.fat.allocate(last, (num_cluster - exist_fat) as u32, contiguous).await
+            .map_err(FileError::from_alloc)?;
+        // empty file without cluster, the item points to the new chain
+        if last == 0 { self.update_item(first, 0).await; }
+        Ok(is_contiguous && new_contiguous)
     }
 
     /// Get Clusters The File Has
     fn num_cluster(&self, length: usize) -> usize {
-        let spc = self.bpb.sector_per_cluster_usize();
+        let spc = self.volume.bpb.sector_per_cluster_usize();
         let cluster_size = spc * BUFFER_SIZE;
-        if length % cluster_size != 0 {
+        if !length.is_multiple_of(cluster_size) {
             length / cluster_size + 1
         } else {
             length / cluster_size
@@ -152,147 +394,206 @@ impl<'a, T> File<'a, T>
         }
     }
 
-    /// Fill Left Sector
-    fn fill_left_sector(&self, buf: &[u8], cluster: u32) -> (bool, usize) {
-        let spc = self.bpb.sector_per_cluster_usize();
-        let length = self.detail.length().unwrap();
-        let get_used_sector = |len: usize| if len % (spc * BUFFER_SIZE) == 0 && length != 0 {
-            spc
-        } else {
-            len % (spc * BUFFER_SIZE) / BUFFER_SIZE
-        };
+    /// Fill Left Sector, The Last Sector Of File Which Is Part Of A Sector
+    ///
+    /// Return count of bytes of buf written to it
+    async fn fill_left_sector(&self, buf: &[u8], cluster: u32) -> usize {
+        let length = self.length();
         let left_start = length % BUFFER_SIZE;
-        let blank_size = BUFFER_SIZE - left_start;
+        if left_start == 0 { return 0; }
 
-        let mut already_fill = 0;
-        let mut buf_has_left = true;
-        let mut index = 0;
-        let mut used_sector = get_used_sector(length);
+        let cluster_size = self.volume.bpb.sector_per_cluster_usize() * BUFFER_SIZE;
+        let sector = self.volume.bpb.sector(cluster) + (length % cluster_size / BUFFER_SIZE) as u32;
         let mut data = [0; BUFFER_SIZE];
-        let mut offset = self.bpb.offset(cluster) + used_sector * BUFFER_SIZE;
-
-        if left_start != 0 {
-            self.device.read(&mut data, offset, 1).unwrap();
-            if buf.len() <= blank_size {
-                data[left_start..left_start + buf.len()]
-                    .copy_from_slice(&buf[0..]);
-                buf_has_left = false;
-            } else {
-                data[left_start..].copy_from_slice(&buf[0..blank_size]);
-                already_fill = blank_size;
-                index = already_fill;
-                used_sector = get_used_sector(length + already_fill);
-                buf_has_left = true;
-            };
-            self.device.write(&data, offset, 1).unwrap();
-            offset = self.bpb.offset(cluster) + BUFFER_SIZE;
+        self.volume.device.read(&mut data, sector_address(sector), 1).await.unwrap();
+        let filled = cmp::min(buf.len(), BUFFER_SIZE - left_start);
+        data[left_start..left_start + filled].copy_from_slice(&buf[0..filled]);
+        self.volume.device.write(&data, sector_address(sector), 1).await.unwrap();
+        filled
+    }
+
+    /// Update First Cluster AND Length Of File In One Sector Write
+    pub(crate) async fn update_item(&mut self, cluster: u32, length: usize) {
+        let fat = self.fat.with_start(self.position.dir_cluster);
+        let mut iter = DirIter::new(&self.volume.device, fat, &self.volume.bpb);
+        iter.seek(self.position.index).await.unwrap();
+
+        self.detail.set_cluster(cluster);
+        self.detail.set_file_length(length);
+        iter.update_item(&self.detail.bytes()).await.unwrap();
+        iter.update().await;
+        if let Some(slot) = self.slot {
+            slot.set(OpenFile {
+                cluster,
+                length,
+                ..slot.get()
+            });
         }
+    }
 
-        if buf_has_left {
-            let buf_needed_sector = get_needed_sector(buf.len() - already_fill);
-            let the_cluster_left_sector = spc - used_sector;
-            let num_sector = cmp::min(the_cluster_left_sector,
-                                      buf_needed_sector);
-            for s in 0..num_sector {
-                self.buf_write(&buf[index..], s, &mut data);
-                self.device.write(&data,
-                                  offset + s * BUFFER_SIZE,
-                                  1).unwrap();
-                index += BUFFER_SIZE;
-            }
+    /// Get File Length, Files In The Same Slot Of Open File Table Share It
+    pub(crate) fn length(&self) -> usize {
+        match self.slot {
+            Some(slot) => slot.get().length,
+            None => self.detail.length().unwrap(),
+        }
+    }
 
-            if buf_needed_sector > the_cluster_left_sector { return (true, index); }
+    /// Get First Cluster, Files In The Same Slot Of Open File Table Share It
+    pub(crate) fn cluster(&self) -> u32 {
+        match self.slot {
+            Some(slot) => slot.get().cluster,
+            None => self.detail.cluster(),
         }
+    }
 
-        (false, 0)
+    /// Check if the file has length but no cluster, empty file may have first cluster 0
+    fn has_no_chain(&self) -> bool {
+        self.cluster() == 0 && self.length() != 0
     }
 
-    /// Update File Length
-    fn update_length(&mut self, length: usize) {
-        let fat = FAT::new(self.dir_cluster, self.device, self.bpb.fat1());
-        let mut iter = DirIter::new(self.device, fat, self.bpb);
-        iter.find(|d| {
-            !d.is_deleted() && !d.is_lfn() && d.cluster() == self.detail.cluster()
-        }).unwrap();
+    /// Get chain of the file from its first cluster
+    fn chain(&self) -> FAT<'a, T> {
+        self.fat.with_start(self.cluster())
+    }
 
-        self.detail.set_file_length(length);
-        iter.previous();
-        iter.update_item(&self.detail.bytes());
-        iter.update();
-    }
-
-    /// Write Blank FAT
-    fn write_blank_fat(&mut self, num_cluster: usize) {
-        for n in 0..num_cluster {
-            let bl1 = self.fat.blank_cluster();
-            self.fat.write(bl1, 0x0FFFFFFF);
-            let bl2 = self.fat.blank_cluster();
-            if n != num_cluster - 1 {
-                self.fat.write(bl1, bl2);
+    /// Basic Write Function, skip sectors of the first cluster are left as they are
+    async fn _write(&self, buf: &[u8], fat: &FAT<'a, T>, skip: usize) {
+        let mut buf_write = [0; BUFFER_SIZE];
+        let mut transfers = self.transfers(fat, skip, get_needed_sector(buf.len()));
+        while let Some((sector, count, done)) = transfers.next_transfer().await {
+            let index = done * BUFFER_SIZE;
+            // the last sector may be part of a sector, write it through buf_write
+            let full = cmp::min(count, (buf.len() - index) / BUFFER_SIZE);
+            if full != 0 {
+                self.volume.device.write(&buf[index..index + full * BUFFER_SIZE],
+                                         sector_address(sector),
+                                         full).await.unwrap();
+            }
+            if full < count {
+                self.buf_write(buf, done + full, &mut buf_write);
+                self.volume.device.write(&buf_write,
+                                         sector_address(sector + full as u32),
+                                         1).await.unwrap();
             }
         }
     }
 
-    /// Basic Write Function
-    fn _write(&self, buf: &[u8], fat: &FAT<T>) {
-        let spc = self.bpb.sector_per_cluster_usize();
-        let mut buf_write = [0; BUFFER_SIZE];
-        let mut write_count = get_needed_sector(buf.len());
-        let op = |start: usize, sectors: usize| -> &[u8] {
-            &buf[start * BUFFER_SIZE..(start + sectors) * BUFFER_SIZE]
-        };
+    /// Get Runs Of Consecutive Sectors In The Chain, Up To sectors After skip Sectors
+    ///
+    /// A run is no longer than blocks_per_transfer
+    fn transfers(&self, fat: &FAT<'a, T>, skip: usize, sectors: usize) -> Transfers<'a, T> {
+        Transfers {
+            extents: Extents::new(&self.volume.bpb, *fat, skip, sectors),
+            limit: self.volume.blocks_per_transfer,
+            run: None,
+            sectors,
+            done: 0,
+        }
+    }
+}
 
-        let mut w = 0;
-        fat.map(|f| {
-            let count = if write_count / spc > 0 {
-                write_count -= spc;
-                spc
-            } else {
-                write_count
-            };
+impl OpenOptions {
+    /// Options Of open_file() AND Volume::file(), Read AND Write
+    pub(crate) const READ_WRITE: OpenOptions = OpenOptions {
+        read: true,
+        write: true,
+        append: false,
+        truncate: false,
+        create: false,
+        create_new: false,
+    };
+
+    /// Make Options Which Are All false
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-            let offset = self.bpb.offset(f.current_cluster);
-            if count == spc {
-                if (w + spc) * BUFFER_SIZE > buf.len() {
-                    self.buf_write(&buf, w, &mut buf_write);
-                    self.device.write(&buf_write,
-                                      offset,
-                                      1).unwrap();
-                } else {
-                    self.device.write(op(w, count),
-                                      offset,
-                                      count).unwrap();
-                }
-                w += count;
-            } else {
-                self.device.write(op(w, count - 1),
-                                  offset,
-                                  count - 1).unwrap();
-                w += count - 1;
-                self.buf_write(&buf, w, &mut buf_write);
-                self.device.write(&buf_write,
-                                  offset + (count - 1) * BUFFER_SIZE,
-                                  1).unwrap();
-            }
-        }).last();
+    /// Read The File
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    /// Write The File, WriteType Of Each Write Is Used
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Write The File, Every Write Is Append
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    /// Make Length Of Existing File 0, It Needs write
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Create The File If It Doesn't Exist, It Needs write OR append
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Create The File, FileHasExist If It Exists, create AND truncate Are Ignored
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Open File In The Dir With The Options, Return File<T> Type
+    ///
+    /// Return InvalidOptions if nothing is readable OR writable, truncate is without write OR with append,
+    /// OR create is without write AND append
+    pub fn open<'a, T>(&self, dir: &mut Dir<'a, T>, file: &str) -> Result<File<'a, T>, DirError>
+        where T: BlockDevice,
+              <T as BlockDevice>::Error: core::fmt::Debug {
+        block_on(dir.open_with(file, *self))
+    }
+
+    /// Open File In The Dir With The Options, Async Version Of open()
+    pub async fn open_async<'a, T>(&self, dir: &mut Dir<'a, T>, file: &str) -> Result<File<'a, T>, DirError>
+        where T: AsyncBlockDevice,
+              <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+        dir.open_with(file, *self).await
+    }
+
+    pub(crate) fn is_writable(&self) -> bool {
+        self.write || self.append
+    }
+
+    pub(crate) fn is_creating(&self) -> bool {
+        self.create || self.create_new
+    }
+
+    /// Check the options like std does
+    pub(crate) fn is_valid(&self) -> bool {
+        if !self.read && !self.is_writable() { return false; }
+        if self.truncate && (!self.write || self.append) { return false; }
+        !self.is_creating() || self.is_writable()
     }
 }
 
 impl<'a, T> Iterator for ReadIter<'a, T>
-    where T: BlockDevice + Clone + Copy,
+    where T: BlockDevice,
           <T as BlockDevice>::Error: core::fmt::Debug {
     type Item = ([u8; BUFFER_SIZE], usize);
 
     fn next(&mut self) -> Option<Self::Item> {
         let spc = self.bpb.sector_per_cluster_usize();
         if self.read_count == self.need_count { return None; }
-        if self.read_count % spc == 0 { self.fat.next(); }
-
-        let offset = self.bpb.offset(self.fat.current_cluster)
-            + (self.read_count % spc) * BUFFER_SIZE;
-        self.device.read(&mut self.buffer,
-                         offset,
-                         1).unwrap();
+        if self.read_count.is_multiple_of(spc) && self.fat.next().is_none() { return None; }
+
+        let sector = self.bpb.sector(self.fat.current_cluster)
+            + (self.read_count % spc) as u32;
+        BlockDevice::read(self.device,
+                          &mut self.buffer,
+                          sector_address(sector),
+                          1).unwrap();
         self.read_count += 1;
 
         Some(if self.read_count == self.need_count {
@@ -302,4 +603,92 @@ impl<'a, T> Iterator for ReadIter<'a, T>
             (self.buffer, BUFFER_SIZE)
         })
     }
-}
\ No newline at end of file
+}
+
+impl<'a, T> Extents<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    fn new(bpb: &'a BIOSParameterBlock, fat: FAT<'a, T>, skip: usize, sectors: usize) -> Extents<'a, T> {
+        Extents::<T> {
+            bpb,
+            fat,
+            left_sector: sectors,
+            next_sector: None,
+            skip,
+        }
+    }
+
+    /// Check if the chain is broken OR loops, extents stop there
+    pub fn is_corrupted(&self) -> bool {
+        self.fat.is_corrupted()
+    }
+
+    async fn next_run(&mut self) -> Option<(u32, usize)> {
+        if self.left_sector == 0 { return None; }
+        let spc = self.bpb.sector_per_cluster_usize();
+        let (start, mut count) = match self.next_sector.take() {
+            Some(sector) => (sector, cmp::min(spc, self.left_sector)),
+            None => {
+                // only the first cluster has skipped sectors
+                let skip = core::mem::take(&mut self.skip);
+                let sector = self.bpb.sector(self.fat.follow().await?.current_cluster) + skip as u32;
+                (sector, cmp::min(spc - skip, self.left_sector))
+            }
+        };
+        self.left_sector -= count;
+        while self.left_sector != 0 {
+            let sector = match self.fat.follow().await {
+                Some(f) => self.bpb.sector(f.current_cluster),
+                None => break,
+            };
+            // the cluster starts another run
+            if sector != start + count as u32 {
+                self.next_sector = Some(sector);
+                break;
+            }
+            let n = cmp::min(spc, self.left_sector);
+            count += n;
+            self.left_sector -= n;
+        }
+
+        Some((start, count))
+    }
+}
+
+impl<'a, T> Iterator for Extents<'a, T>
+    where T: BlockDevice,
+          <T as BlockDevice>::Error: core::fmt::Debug {
+    type Item = (u32, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        block_on(self.next_run())
+    }
+}
+
+impl<'a, T> Transfers<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    async fn next_transfer(&mut self) -> Option<(u32, usize, usize)> {
+        let (start, count) = match self.run.take() {
+            Some(run) => run,
+            None => self.extents.next_run().await?,
+        };
+        let n = cmp::min(count, self.limit);
+        if n < count { self.run = Some((start + n as u32, count - n)); }
+
+        let done = self.done;
+        self.done += n;
+        Some((start, n, done))
+    }
+
+    /// Check if all sectors were got, false if the chain is broken OR shorter
+    fn is_complete(&self) -> bool {
+        !self.extents.is_corrupted() && self.done == self.sectors
+    }
+
+    /// Follow the chain after the sectors to its end, false if it is broken OR loops
+    async fn is_rest_valid(&mut self) -> bool {
+        self.extents.fat.follow_to_end().await;
+        !self.extents.is_corrupted()
+    }
+}
@@ -0,0 +1,34 @@
+/// Define HandleError
+#[derive(Debug, PartialOrd, PartialEq)]
+pub enum HandleError {
+    OtherVolume,
+    Stale,
+    TooManyOpenFiles,
+}
+
+/// Where The Item Is, Cluster Of Its Dir AND Its Index In The Dir
+///
+/// Items are appended at the end of dir AND deleted in place, so the index doesn't change
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct Position {
+    pub(crate) dir_cluster: u32,
+    pub(crate) index: usize,
+}
+
+/// File Handle Borrows Nothing, It Can Be Stored Next To The Volume
+///
+/// Get File back by Volume::file(), the item is read again, so length is up to date
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FileHandle {
+    pub(crate) id: u32,
+    pub(crate) position: Position,
+}
+
+/// Dir Handle Borrows Nothing, It Can Be Stored Next To The Volume
+///
+/// Get Dir back by Volume::dir(), position is None for root dir
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DirHandle {
+    pub(crate) id: u32,
+    pub(crate) position: Option<Position>,
+}
@@ -0,0 +1,41 @@
+use std::fs::File;
+use std::io::{
+    Error,
+    Read,
+    Seek,
+    SeekFrom,
+    Write,
+};
+use block_device::BlockDevice;
+use crate::BUFFER_SIZE;
+use crate::tool::address_offset;
+
+/// BlockDevice Over A Disk Image File, Every Copy Shares The File
+#[derive(Debug, Copy, Clone)]
+pub struct ImageFile<'a> {
+    file: &'a File,
+}
+
+impl<'a> ImageFile<'a> {
+    pub fn new(file: &'a File) -> Self {
+        Self {
+            file
+        }
+    }
+}
+
+impl<'a> BlockDevice for ImageFile<'a> {
+    type Error = Error;
+
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        let mut file = self.file;
+        file.seek(SeekFrom::Start(address_offset(address)))?;
+        file.read_exact(&mut buf[0..number_of_blocks * BUFFER_SIZE])
+    }
+
+    fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        let mut file = self.file;
+        file.seek(SeekFrom::Start(address_offset(address)))?;
+        file.write_all(&buf[0..number_of_blocks * BUFFER_SIZE])
+    }
+}
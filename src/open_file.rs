@@ -0,0 +1,20 @@
+use crate::handle::Position;
+
+/// Slot Of Open File Table, See Volume::with_open_files()
+///
+/// Files opened at the same position share the slot, it holds first cluster AND length,
+/// so every File of the position sees what the others wrote
+#[derive(Debug, Copy, Clone, Default)]
+pub struct OpenFile {
+    pub(crate) position: Option<Position>,
+    pub(crate) count: usize,
+    pub(crate) cluster: u32,
+    pub(crate) length: usize,
+}
+
+impl OpenFile {
+    /// Check if the slot is used by the position
+    pub(crate) fn is_of(&self, position: Position) -> bool {
+        self.position == Some(position)
+    }
+}
@@ -0,0 +1,99 @@
+use core::cell::Cell;
+use core::fmt::{
+    Debug,
+    Formatter,
+};
+use core::ops::Range;
+use block_device::BlockDevice;
+use crate::BUFFER_SIZE;
+use crate::read_only::ReadBlockDevice;
+use crate::tool::address_offset;
+
+/// Define DiskError
+#[derive(Debug, PartialOrd, PartialEq)]
+pub enum DiskError {
+    OutOfRange,
+}
+
+/// Get bytes of the blocks at address, if they are in the disk
+fn block_range(size: usize, address: usize, number_of_blocks: usize) -> Result<Range<usize>, DiskError> {
+    let start = address_offset(address);
+    let end = start + (number_of_blocks * BUFFER_SIZE) as u64;
+    if end > size as u64 { return Err(DiskError::OutOfRange); }
+    Ok(start as usize..end as usize)
+}
+
+/// BlockDevice Over Memory, Every Copy Shares The Memory
+#[derive(Copy, Clone)]
+pub struct RamDisk<'a> {
+    data: &'a [Cell<u8>],
+}
+
+impl<'a> RamDisk<'a> {
+    pub fn new(data: &'a mut [u8]) -> Self {
+        Self {
+            data: Cell::from_mut(data).as_slice_of_cells()
+        }
+    }
+}
+
+impl<'a> BlockDevice for RamDisk<'a> {
+    type Error = DiskError;
+
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        let range = block_range(self.data.len(), address, number_of_blocks)?;
+        for (to, from) in buf.iter_mut().zip(&self.data[range]) {
+            *to = from.get();
+        }
+        Ok(())
+    }
+
+    fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        let range = block_range(self.data.len(), address, number_of_blocks)?;
+        for (to, from) in self.data[range].iter().zip(buf) {
+            to.set(*from);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Debug for RamDisk<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("RamDisk")
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
+
+/// Read-Only Device Over Memory Like Flash, Mount It With Volume::read_only()
+#[derive(Copy, Clone)]
+pub struct RomDisk<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> RomDisk<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data
+        }
+    }
+}
+
+impl<'a> ReadBlockDevice for RomDisk<'a> {
+    type Error = DiskError;
+
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        let range = block_range(self.data.len(), address, number_of_blocks)?;
+        let len = core::cmp::min(buf.len(), range.len());
+        buf[0..len].copy_from_slice(&self.data[range][0..len]);
+        Ok(())
+    }
+}
+
+impl<'a> Debug for RomDisk<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("RomDisk")
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
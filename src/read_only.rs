@@ -0,0 +1,46 @@
+use block_device::BlockDevice;
+
+/// Device which only implements read, every BlockDevice is also one
+pub trait ReadBlockDevice {
+    type Error;
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error>;
+}
+
+impl<T> ReadBlockDevice for T
+    where T: BlockDevice {
+    type Error = <T as BlockDevice>::Error;
+
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        BlockDevice::read(self, buf, address, number_of_blocks)
+    }
+}
+
+/// Wrap ReadBlockDevice For Read-Only Volume, It Never Writes To The Device
+#[derive(Debug, Copy, Clone)]
+pub struct ReadOnly<T>
+    where T: ReadBlockDevice {
+    device: T,
+}
+
+impl<T> ReadOnly<T>
+    where T: ReadBlockDevice {
+    pub(crate) fn new(device: T) -> Self {
+        Self {
+            device
+        }
+    }
+}
+
+impl<T> BlockDevice for ReadOnly<T>
+    where T: ReadBlockDevice {
+    type Error = <T as ReadBlockDevice>::Error;
+
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        self.device.read(buf, address, number_of_blocks)
+    }
+
+    fn write(&self, _buf: &[u8], _address: usize, _number_of_blocks: usize) -> Result<(), Self::Error> {
+        // every mutating method returns ReadOnly error before any write
+        unreachable!("write to read-only volume")
+    }
+}
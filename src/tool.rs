@@ -3,11 +3,6 @@ use core::str;
 use crate::BUFFER_SIZE;
 use crate::directory_item::NameType;
 
-pub(crate) fn is_fat32(value: &[u8]) -> bool {
-    let file_system_str = str::from_utf8(&value[0..5]).unwrap();
-    file_system_str.eq("FAT32")
-}
-
 pub(crate) fn read_le_u16(input: &[u8]) -> u16 {
     let (int_bytes, _) = input.split_at(core::mem::size_of::<u16>());
     u16::from_le_bytes(int_bytes.try_into().unwrap())
@@ -18,6 +13,35 @@ pub(crate) fn read_le_u32(input: &[u8]) -> u32 {
     u32::from_le_bytes(int_bytes.try_into().unwrap())
 }
 
+/// Get the address BlockDevice takes for the sector, mount checks the last sector has one
+pub(crate) fn sector_address(sector: u32) -> usize {
+    checked_sector_address(sector).expect("byte address overflows usize, enable feature lba")
+}
+
+/// Get the address BlockDevice takes for the sector, the sector itself with feature lba
+#[cfg(feature = "lba")]
+pub(crate) fn checked_sector_address(sector: u32) -> Option<usize> {
+    Some(sector as usize)
+}
+
+/// Get the address BlockDevice takes for the sector, None if bytes overflow usize without feature lba
+#[cfg(not(feature = "lba"))]
+pub(crate) fn checked_sector_address(sector: u32) -> Option<usize> {
+    (sector as usize).checked_mul(BUFFER_SIZE)
+}
+
+/// Get the byte offset on device of the address BlockDevice takes, with feature lba
+#[cfg(feature = "lba")]
+pub(crate) fn address_offset(address: usize) -> u64 {
+    address as u64 * BUFFER_SIZE as u64
+}
+
+/// Get the byte offset on device of the address BlockDevice takes
+#[cfg(not(feature = "lba"))]
+pub(crate) fn address_offset(address: usize) -> u64 {
+    address as u64
+}
+
 pub(crate) fn is_illegal(chs: &str) -> bool {
     let illegal_char = "\\/:*?\"<>|";
     for ch in illegal_char.chars() {
@@ -49,7 +73,7 @@ pub(crate) fn sfn_or_lfn(value: &str) -> NameType {
 
 pub(crate) fn get_count_of_lfn(value: &str) -> usize {
     let num_char = value.chars().count();
-    if num_char % 13 == 0 { num_char / 13 } else { num_char / 13 + 1 }
+    if num_char.is_multiple_of(13) { num_char / 13 } else { num_char / 13 + 1 }
 }
 
 pub(crate) fn get_lfn_index(value_str: &str, count: usize) -> usize {
@@ -76,7 +100,7 @@ pub(crate) fn generate_checksum(value: &[u8]) -> u8 {
 }
 
 pub(crate) fn get_needed_sector(value: usize) -> usize {
-    if value % BUFFER_SIZE != 0 {
+    if !value.is_multiple_of(BUFFER_SIZE) {
         value / BUFFER_SIZE + 1
     } else {
         value / BUFFER_SIZE
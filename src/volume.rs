@@ -1,93 +1,539 @@
 use core::str;
+use core::cell::Cell;
 use block_device::BlockDevice;
+use crate::async_device::{
+    AsyncBlockDevice,
+    block_on,
+};
 use core::fmt::{
     Debug,
     Formatter,
     Result,
 };
 use crate::tool::{
-    is_fat32,
+    checked_sector_address,
     read_le_u16,
     read_le_u32,
+    sector_address,
+};
+use crate::bpb::{
+    BIOSParameterBlock,
+    FatType,
 };
-use crate::bpb::BIOSParameterBlock;
 use crate::BUFFER_SIZE;
-use crate::dir::Dir;
+use crate::dir::{
+    Dir,
+    DirError,
+    DirIter,
+};
+use crate::file::{
+    File,
+    OpenOptions,
+};
+use crate::handle::{
+    DirHandle,
+    FileHandle,
+    HandleError,
+    Position,
+};
 use crate::directory_item::DirectoryItem;
+use crate::open_file::OpenFile;
 use crate::fat::FAT;
+use crate::read_only::{
+    ReadBlockDevice,
+    ReadOnly,
+};
+use crate::cache::{
+    Cached,
+    SectorCache,
+};
 
-#[derive(Copy, Clone)]
-pub struct Volume<T>
-    where T: BlockDevice + Clone + Copy,
-          <T as BlockDevice>::Error: core::fmt::Debug
+/// Define VolumeError
+#[derive(Debug, PartialOrd, PartialEq)]
+pub enum VolumeError {
+    BitmapTooSmall,
+    /// byte_per_sector of the volume is not BUFFER_SIZE, another size feature is needed
+    SectorSize,
+    /// Byte address of the last sector overflows usize, feature lba is needed
+    TooLarge,
+    /// Boot sector doesn't describe a volume, like sector_per_cluster 0 OR no data cluster
+    BadGeometry,
+    /// Device fails to read the boot sector OR FAT
+    ReadError,
+}
+
+/// FSInfo Signatures AND Offsets Of Free Count AND Next Free Cluster
+const FS_INFO_LEAD: u32 = 0x41615252;
+const FS_INFO_STRUCT: u32 = 0x61417272;
+const FS_INFO_FREE_COUNT: usize = 0x1E8;
+const FS_INFO_NEXT_FREE: usize = 0x1EC;
+/// Free Count OR Next Free Cluster Is Not Known
+const FS_INFO_UNKNOWN: u32 = 0xFFFFFFFF;
+
+/// Default Limit Of Blocks In One Read OR Write Call Of File
+pub const DEFAULT_BLOCKS_PER_TRANSFER: usize = 128;
+
+/// Write Back Sectors Which The Device Holds, Like Sectors In Cache
+pub(crate) type WriteBack<T> = fn(&T) -> core::result::Result<(), <T as AsyncBlockDevice>::Error>;
+
+/// Volume Owns The Device, Dir AND File Borrow The Volume
+pub struct Volume<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug
 {
-    device: T,
-    bpb: BIOSParameterBlock,
+    pub(crate) device: T,
+    pub(crate) bpb: BIOSParameterBlock,
+    read_only: bool,
+    bitmap: Option<&'a [Cell<u8>]>,
+    open_files: Option<&'a [Cell<OpenFile>]>,
+    pub(crate) blocks_per_transfer: usize,
+    cleanly_unmounted: bool,
+    /// clean at mount OR check found no problem since, only then flush() sets the clean bit
+    pub(crate) verified: Cell<bool>,
+    /// clean shutdown bit is cleared, FAT[1] is written only when it changes
+    dirty: Cell<bool>,
+    write_back: Option<WriteBack<T>>,
 }
 
-impl<T> Volume<T>
-    where T: BlockDevice + Clone + Copy,
+impl<'a, T> Volume<'a, T>
+    where T: BlockDevice,
           <T as BlockDevice>::Error: core::fmt::Debug {
     /// Make volume from device which implement BlockDevice
-    pub fn new(device: T) -> Volume<T> {
-        let mut buf = [0; BUFFER_SIZE];
-        device.read(&mut buf, 0, 1).unwrap();
+    ///
+    /// Panics if the volume can't be mounted, try_new() returns the error instead
+    pub fn new(device: T) -> Volume<'a, T> {
+        block_on(Self::mount(device)).unwrap_or_else(|(_, e)| panic!("can't mount volume: {:?}", e))
+    }
+
+    /// Make Volume From Device, The Device Comes Back With The Error If It Can't Be Mounted
+    ///
+    /// Return SectorSize if byte_per_sector isn't BUFFER_SIZE, BadGeometry if the boot sector is garbage,
+    /// TooLarge if the volume is over 4 GiB on 32-bit target without feature lba,
+    /// OR ReadError if the device fails
+    pub fn try_new(device: T) -> core::result::Result<Volume<'a, T>, (T, VolumeError)> {
+        block_on(Self::mount(device))
+    }
+
+    /// Write Back Cached Sectors, Update FSInfo AND Mark The Volume Clean
+    ///
+    /// The clean shutdown bit goes to the device after everything else.
+    /// If the volume was dirty at mount, the bit is set only after check() OR repair() returns 0.
+    /// Modification after it marks the volume dirty again.
+    /// Return the first error of device, the volume stays dirty then
+    pub fn flush(&self) -> core::result::Result<(), <T as BlockDevice>::Error> {
+        block_on(self.flush_async())
+    }
+
+    /// Flush AND Give The Device Back, The Device Comes Back With The Error If It Fails
+    pub fn unmount(self) -> core::result::Result<T, (T, <T as BlockDevice>::Error)> {
+        match self.flush() {
+            Ok(()) => Ok(self.device),
+            Err(e) => Err((self.device, e)),
+        }
+    }
+
+    /// Get File Of The Handle, Its Item Is Read Again
+    ///
+    /// Return Stale if the file was deleted, OR OtherVolume if the handle is of another volume
+    pub fn file(&self, handle: &FileHandle) -> core::result::Result<File<'_, T>, HandleError> {
+        block_on(self.file_async(handle))
+    }
+
+    /// Get Dir Of The Handle, Its Item Is Read Again
+    ///
+    /// Return Stale if the dir was deleted, OR OtherVolume if the handle is of another volume
+    pub fn dir(&self, handle: &DirHandle) -> core::result::Result<Dir<'_, T>, HandleError> {
+        block_on(self.dir_async(handle))
+    }
 
-        let mut volume_label = [0; 11];
-        volume_label.copy_from_slice(&buf[0x47..0x52]);
+    /// Keep Used Clusters In Bitmap, So Allocation Doesn't Read FAT
+    ///
+    /// Whole FAT is read once here, then bitmap is updated on every allocate AND free.
+    /// The volume comes back with the error if bitmap is too small
+    pub fn with_bitmap<'b>(self, bitmap: &'b mut [u8]) -> core::result::Result<Volume<'b, T>, (Volume<'a, T>, VolumeError)>
+        where 'a: 'b {
+        let size = self.bitmap_size();
+        if bitmap.len() < size { return Err((self, VolumeError::BitmapTooSmall)); }
 
-        let mut file_system = [0; 8];
-        file_system.copy_from_slice(&buf[0x52..0x5A]);
+        let bitmap = Cell::from_mut(bitmap).as_slice_of_cells();
+        let mut volume = Volume {
+            bitmap: None,
+            ..self
+        };
+        let max_cluster = volume.bpb.max_cluster();
+        let mut fat = volume.fat(0);
+        for (i, byte) in bitmap.iter().enumerate().take(size) {
+            let mut bits = 0;
+            for bit in 0..8 {
+                let cluster = (i * 8 + bit) as u32;
+                // cluster 0 AND 1 are never free
+                if cluster < 2 || cluster > max_cluster || block_on(fat.get(cluster)) != 0 {
+                    bits |= 1 << bit;
+                }
+            }
+            byte.set(bits);
+        }
+
+        volume.bitmap = Some(bitmap);
+        Ok(volume)
+    }
+}
 
+impl<'a, T> Volume<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
+    /// Make volume from device which implement AsyncBlockDevice, Async Version Of new()
+    pub async fn new_async(device: T) -> Volume<'a, T> {
+        Self::mount(device).await.unwrap_or_else(|(_, e)| panic!("can't mount volume: {:?}", e))
+    }
+
+    /// Make Volume From Device, Async Version Of try_new()
+    pub async fn try_new_async(device: T) -> core::result::Result<Volume<'a, T>, (T, VolumeError)> {
+        Self::mount(device).await
+    }
+
+    async fn mount(device: T) -> core::result::Result<Volume<'a, T>, (T, VolumeError)> {
+        let mut buf = [0; BUFFER_SIZE];
+        if device.read(&mut buf, 0, 1).await.is_err() { return Err((device, VolumeError::ReadError)); }
+
+        // BUFFER_SIZE comes from features, it must be the sector size
         let bps = read_le_u16(&buf[0x0B..0x0D]);
-        if bps as usize != BUFFER_SIZE {
-            panic!("BUFFER_SIZE is {} Bytes, byte_per_sector is {} Bytes\
-            , please edit features",
-                   BUFFER_SIZE, bps);
+        if bps as usize != BUFFER_SIZE { return Err((device, VolumeError::SectorSize)); }
+
+        let total_sector = match read_le_u16(&buf[0x13..0x15]) {
+            0 => read_le_u32(&buf[0x20..0x24]),
+            total_sector => total_sector as u32,
+        };
+
+        let sector_per_fat = match read_le_u16(&buf[0x16..0x18]) {
+            0 => read_le_u32(&buf[0x24..0x28]),
+            sector_per_fat => sector_per_fat as u32,
+        };
+
+        let mut bpb = BIOSParameterBlock {
+            byte_per_sector: bps,
+            sector_per_cluster: buf[0x0D],
+            reserved_sector: read_le_u16(&buf[0x0E..0x10]),
+            num_fat: buf[0x10],
+            root_entry_count: read_le_u16(&buf[0x11..0x13]),
+            total_sector,
+            sector_per_fat,
+            root_cluster: 0,
+            fs_info: 0,
+            id: 0,
+            volume_label: [0; 11],
+            fat_type: FatType::FAT32,
+        };
+
+        // nothing is computed from the geometry before it is checked
+        let first_data_sector = bpb.reserved_sector as u64
+            + bpb.num_fat as u64 * bpb.sector_per_fat as u64
+            + bpb.root_dir_sectors() as u64;
+        if !bpb.sector_per_cluster.is_power_of_two() || bpb.num_fat == 0 || bpb.sector_per_fat == 0
+            || first_data_sector >= bpb.total_sector as u64 || bpb.cluster_count() == 0 {
+            return Err((device, VolumeError::BadGeometry));
+        }
+
+        // FAT type is determined by count of clusters, not by the label
+        bpb.fat_type = FatType::from_cluster_count(bpb.cluster_count());
+        match bpb.fat_type {
+            FatType::FAT32 => {
+                bpb.root_cluster = read_le_u32(&buf[0x2C..0x30]);
+                bpb.fs_info = read_le_u16(&buf[0x30..0x32]) as u32;
+                bpb.id = read_le_u32(&buf[0x43..0x47]);
+                bpb.volume_label.copy_from_slice(&buf[0x47..0x52]);
+            }
+            FatType::FAT12 | FatType::FAT16 => {
+                bpb.id = read_le_u32(&buf[0x27..0x2B]);
+                bpb.volume_label.copy_from_slice(&buf[0x2B..0x36]);
+            }
         }
 
-        // if not fat32 file system, panic
-        if !is_fat32(&file_system) { panic!("not fat32 file system"); }
+        if bpb.fat_type == FatType::FAT32 && (bpb.root_cluster < 2 || bpb.root_cluster > bpb.max_cluster()) {
+            return Err((device, VolumeError::BadGeometry));
+        }
+
+        // fail here, not after writing to the wrapped address
+        if checked_sector_address(bpb.total_sector - 1).is_none() { return Err((device, VolumeError::TooLarge)); }
 
-        Volume::<T> {
+        let cleanly_unmounted = match FAT::new(0, &device, &bpb, None).is_clean().await {
+            Ok(clean) => clean,
+            Err(_) => return Err((device, VolumeError::ReadError)),
+        };
+        Ok(Volume::<T> {
             device,
-            bpb: BIOSParameterBlock {
-                byte_per_sector: bps,
-                sector_per_cluster: buf[0x0D],
-                reserved_sector: read_le_u16(&buf[0x0E..0x10]),
-                num_fat: buf[0x10],
-                total_sector: read_le_u32(&buf[0x20..0x24]),
-                sector_per_fat: read_le_u32(&buf[0x24..0x28]),
-                root_cluster: read_le_u32(&buf[0x2C..0x30]),
-                id: read_le_u32(&buf[0x43..0x47]),
-                volume_label,
-                file_system,
-            },
+            bpb,
+            read_only: false,
+            bitmap: None,
+            open_files: None,
+            blocks_per_transfer: DEFAULT_BLOCKS_PER_TRANSFER,
+            cleanly_unmounted,
+            verified: Cell::new(cleanly_unmounted),
+            dirty: Cell::new(!cleanly_unmounted),
+            write_back: None,
+        })
+    }
+
+    /// Check If The Volume Was Unmounted Cleanly Last Time, Got At Mount
+    ///
+    /// The clean shutdown bit in FAT[1] is cleared by the first modification
+    /// AND set again by flush(), but only after check() returns 0 if it was false.
+    /// FAT12 has no such bit, it is always true
+    pub fn was_cleanly_unmounted(&self) -> bool {
+        self.cleanly_unmounted
+    }
+
+    /// Write Back, Update FSInfo AND Mark The Volume Clean, Async Version Of flush()
+    pub async fn flush_async(&self) -> core::result::Result<(), <T as AsyncBlockDevice>::Error> {
+        if self.read_only { return Ok(()); }
+        self.write_back()?;
+        if self.dirty.get() {
+            self.update_fs_info().await?;
+            self.write_back()?;
+            // a volume dirty at mount may be corrupted, it stays dirty till check finds nothing
+            if self.verified.get() {
+                self.fat(0).set_clean(true).await?;
+                self.write_back()?;
+                self.dirty.set(false);
+            }
+        }
+        Ok(())
+    }
+
+    /// Clear the clean shutdown bit before the first modification, later ones don't touch FAT[1]
+    pub(crate) async fn mark_dirty(&self) {
+        if !self.dirty.get() {
+            self.fat(0).set_clean(false).await.unwrap();
+            self.dirty.set(true);
         }
     }
 
+    /// Flush AND Give The Device Back, Async Version Of unmount()
+    pub async fn unmount_async(self) -> core::result::Result<T, (T, <T as AsyncBlockDevice>::Error)> {
+        match self.flush_async().await {
+            Ok(()) => Ok(self.device),
+            Err(e) => Err((self.device, e)),
+        }
+    }
+
+    pub(crate) fn write_back(&self) -> core::result::Result<(), <T as AsyncBlockDevice>::Error> {
+        match self.write_back {
+            Some(write_back) => write_back(&self.device),
+            None => Ok(()),
+        }
+    }
+
+    /// Write Free Count AND Next Free Cluster To FSInfo Of FAT32
+    ///
+    /// They are counted in bitmap. Without bitmap, counting reads the whole FAT,
+    /// so they are written as unknown, which makes the OS count them
+    async fn update_fs_info(&self) -> core::result::Result<(), <T as AsyncBlockDevice>::Error> {
+        let sector = self.bpb.fs_info;
+        if self.bpb.fat_type != FatType::FAT32 || sector == 0 || sector == 0xFFFF { return Ok(()); }
+
+        let mut buf = [0; BUFFER_SIZE];
+        self.device.read(&mut buf, sector_address(sector), 1).await?;
+        if read_le_u32(&buf[0..4]) != FS_INFO_LEAD || read_le_u32(&buf[484..488]) != FS_INFO_STRUCT {
+            return Ok(());
+        }
+
+        let (free_count, next_free) = match self.bitmap {
+            Some(bitmap) => {
+                let max_cluster = self.bpb.max_cluster();
+                let mut free_count = 0;
+                let mut next_free = FS_INFO_UNKNOWN;
+                for cluster in 2..=max_cluster {
+                    if bitmap[cluster as usize / 8].get() & (1 << (cluster % 8)) == 0 {
+                        if free_count == 0 { next_free = cluster; }
+                        free_count += 1;
+                    }
+                }
+                (free_count, next_free)
+            }
+            None => (FS_INFO_UNKNOWN, FS_INFO_UNKNOWN),
+        };
+        buf[FS_INFO_FREE_COUNT..FS_INFO_FREE_COUNT + 4].copy_from_slice(&free_count.to_le_bytes());
+        buf[FS_INFO_NEXT_FREE..FS_INFO_NEXT_FREE + 4].copy_from_slice(&next_free.to_le_bytes());
+        self.device.write(&buf, sector_address(sector), 1).await
+    }
+
+    /// Get Bytes Of Bitmap Which with_bitmap() Needs, One Bit Per Cluster
+    pub fn bitmap_size(&self) -> usize {
+        (self.bpb.max_cluster() as usize + 1).div_ceil(8)
+    }
+
+    /// Set Limit Of Blocks In One Read OR Write Call Of File
+    ///
+    /// Consecutive clusters are read AND written together, up to the limit
+    pub fn with_blocks_per_transfer(self, blocks: usize) -> Volume<'a, T> {
+        assert_ne!(blocks, 0, "blocks per transfer can't be 0");
+        Volume {
+            blocks_per_transfer: blocks,
+            ..self
+        }
+    }
+
+    /// Track Open Files In table, Deleting A File OR Dir Which Has One Returns FileIsOpen
+    ///
+    /// Files opened at the same position share a slot, so they see the same length.
+    /// Opening fails with TooManyOpenFiles when every slot is used, dropping the last File frees it
+    pub fn with_open_files<'b>(self, table: &'b mut [OpenFile]) -> Volume<'b, T>
+        where 'a: 'b {
+        table.fill(OpenFile::default());
+        let mut volume: Volume<'b, T> = self;
+        volume.open_files = Some(Cell::from_mut(table).as_slice_of_cells());
+        volume
+    }
+
+    /// Take slot of the position in open file table, cluster AND length fill a new slot
+    ///
+    /// Return None without table, TooManyOpenFiles if every slot is used
+    pub(crate) fn open_slot(&self, position: Position, cluster: u32, length: usize)
+                            -> core::result::Result<Option<&'a Cell<OpenFile>>, DirError> {
+        let table = match self.open_files {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        let slot = table.iter().find(|slot| slot.get().is_of(position))
+            .or_else(|| table.iter().find(|slot| slot.get().count == 0))
+            .ok_or(DirError::TooManyOpenFiles)?;
+
+        let mut open = slot.get();
+        if open.count == 0 {
+            open = OpenFile {
+                position: Some(position),
+                count: 0,
+                cluster,
+                length,
+            };
+        }
+        open.count += 1;
+        slot.set(open);
+        Ok(Some(slot))
+    }
+
+    /// Get positions of open files, nothing without table
+    pub(crate) fn open_positions(&self) -> impl Iterator<Item=Position> + '_ {
+        self.open_files.unwrap_or(&[]).iter().filter_map(|slot| slot.get().position)
+    }
+
+    /// Get FAT which starts from the cluster, it shares the bitmap of volume
+    pub(crate) fn fat(&self, cluster: u32) -> FAT<'_, T> {
+        FAT::new(cluster, &self.device, &self.bpb, self.bitmap)
+    }
+
     /// Get Volume Label
     pub fn volume_label(&self) -> &str {
         str::from_utf8(&self.bpb.volume_label).unwrap()
     }
 
+    /// Get FAT Type
+    pub fn fat_type(&self) -> FatType {
+        self.bpb.fat_type
+    }
+
     /// Cd root dir, its Dir<T> Type
-    pub fn root_dir(&self) -> Dir<T> {
+    pub fn root_dir(&self) -> Dir<'_, T> {
         Dir::<T> {
-            device: self.device,
-            bpb: &self.bpb,
+            volume: self,
+            position: None,
             detail: DirectoryItem::root_dir(self.bpb.root_cluster),
-            fat: FAT::new(self.bpb.root_cluster,
-                          self.device,
-                          self.bpb.fat1()),
+            fat: self.fat(self.bpb.root_cluster),
         }
     }
+
+    /// Get File Of The Handle, Async Version Of file()
+    pub async fn file_async(&self, handle: &FileHandle) -> core::result::Result<File<'_, T>, HandleError> {
+        let di = self.item(handle.id, handle.position).await?;
+        if !di.is_file() { return Err(HandleError::Stale); }
+        let slot = self.open_slot(handle.position, di.cluster(), di.length().unwrap())
+            .map_err(|_| HandleError::TooManyOpenFiles)?;
+        Ok(File::<T> {
+            volume: self,
+            position: handle.position,
+            detail: di,
+            fat: self.fat(di.cluster()),
+            slot,
+            options: OpenOptions::READ_WRITE,
+        })
+    }
+
+    /// Get Dir Of The Handle, Async Version Of dir()
+    pub async fn dir_async(&self, handle: &DirHandle) -> core::result::Result<Dir<'_, T>, HandleError> {
+        let position = match handle.position {
+            Some(position) => position,
+            None if handle.id == self.bpb.id => return Ok(self.root_dir()),
+            None => return Err(HandleError::OtherVolume),
+        };
+        let di = self.item(handle.id, position).await?;
+        if !di.is_dir() { return Err(HandleError::Stale); }
+        Ok(Dir::<T> {
+            volume: self,
+            position: Some(position),
+            detail: di,
+            fat: self.fat(di.cluster()),
+        })
+    }
+
+    /// Read item at the position, it must not be deleted AND its first cluster must be used
+    ///
+    /// Chains in a deleted dir are freed, so items left in it are stale too.
+    /// Empty file may have first cluster 0, there is no chain to check then
+    async fn item(&self, id: u32, position: Position) -> core::result::Result<DirectoryItem, HandleError> {
+        if id != self.bpb.id { return Err(HandleError::OtherVolume); }
+        let mut iter = DirIter::new(&self.device, self.fat(position.dir_cluster), &self.bpb);
+        match iter.seek(position.index).await {
+            Some(di) if !di.is_deleted() && !di.is_lfn() => {
+                let cluster = di.cluster();
+                if di.is_file() && cluster == 0 { return Ok(di); }
+                let used = cluster >= 2 && cluster <= self.bpb.max_cluster()
+                    && self.fat(0).get(cluster).await != 0;
+                if used { Ok(di) } else { Err(HandleError::Stale) }
+            }
+            _ => Err(HandleError::Stale),
+        }
+    }
+
+    /// Check if volume is mounted read-only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 }
 
-/// implement Debug Display for Volume
-impl<T> Debug for Volume<T>
-    where T: BlockDevice + Clone + Copy,
+/// Read-Only Volume, Every Mutating Method Returns ReadOnly Error
+pub type ReadOnlyVolume<'a, T> = Volume<'a, ReadOnly<T>>;
+
+impl<'a, T> Volume<'a, ReadOnly<T>>
+    where T: ReadBlockDevice,
+          <T as ReadBlockDevice>::Error: core::fmt::Debug {
+    /// Make read-only volume from device which implement ReadBlockDevice
+    pub fn read_only(device: T) -> ReadOnlyVolume<'a, T> {
+        Volume {
+            read_only: true,
+            ..Volume::new(ReadOnly::new(device))
+        }
+    }
+}
+
+/// Volume Whose Sectors Are Cached, Call flush() Before Removing The Device
+pub type CachedVolume<'a, T, const N: usize> = Volume<'a, Cached<'a, T, N>>;
+
+impl<'a, T, const N: usize> Volume<'a, Cached<'a, T, N>>
+    where T: BlockDevice,
           <T as BlockDevice>::Error: core::fmt::Debug {
+    /// Make volume from device, sectors are cached in cache
+    pub fn cached(device: T, cache: &'a SectorCache<N>) -> CachedVolume<'a, T, N> {
+        Volume {
+            write_back: Some(Cached::flush),
+            ..Volume::new(Cached::new(device, cache))
+        }
+    }
+}
+
+/// implement Debug Display for Volume
+impl<'a, T> Debug for Volume<'a, T>
+    where T: AsyncBlockDevice,
+          <T as AsyncBlockDevice>::Error: core::fmt::Debug {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         f.debug_struct("Volume")
             .field("byte_per_sector", &self.bpb.byte_per_sector)
@@ -96,10 +542,11 @@ impl<T> Debug for Volume<T>
             .field("num_fat", &self.bpb.num_fat)
             .field("total_sector", &self.bpb.total_sector)
             .field("sector_per_fat", &self.bpb.sector_per_fat)
+            .field("root_entry_count", &self.bpb.root_entry_count)
             .field("root_cluster", &self.bpb.root_cluster)
             .field("id", &self.bpb.id)
             .field("volume_label", &self.volume_label().trim())
-            .field("file_system", &"FAT32")
+            .field("file_system", &self.bpb.fat_type)
             .finish()
     }
 }
\ No newline at end of file
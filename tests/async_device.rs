@@ -0,0 +1,151 @@
+mod common;
+
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::pin;
+use std::task::{
+    Context,
+    Poll,
+    Waker,
+};
+use block_device::BlockDevice;
+use common::{
+    pattern,
+    ram_image,
+    RAM_IMAGES,
+};
+use fat32::async_device::AsyncBlockDevice;
+use fat32::check::{
+    bitmap_size,
+    check,
+};
+use fat32::dir::DirError;
+use fat32::file::WriteType;
+use fat32::ram_disk::{
+    DiskError,
+    RamDisk,
+};
+use fat32::volume::Volume;
+
+/// Future which is pending once, like a transfer which waits for DMA
+struct Yield {
+    yielded: bool,
+}
+
+impl Future for Yield {
+    type Output = ();
+
+    fn poll(mut self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded { return Poll::Ready(()); }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Async-only device, every transfer yields before it is done
+struct AsyncDisk<'a> {
+    disk: RamDisk<'a>,
+    yields: Cell<usize>,
+}
+
+impl<'a> AsyncBlockDevice for AsyncDisk<'a> {
+    type Error = DiskError;
+
+    async fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        Yield { yielded: false }.await;
+        self.yields.set(self.yields.get() + 1);
+        BlockDevice::read(&self.disk, buf, address, number_of_blocks)
+    }
+
+    async fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        Yield { yielded: false }.await;
+        self.yields.set(self.yields.get() + 1);
+        BlockDevice::write(&self.disk, buf, address, number_of_blocks)
+    }
+}
+
+/// Executor of the test, it polls until the future is ready
+fn run<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
+
+#[test]
+fn same_image_as_blocking() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let mut blocking = ram_image(*fat_type, *total_sector, *spc);
+        let mut data = blocking.clone();
+
+        {
+            let volume = Volume::new(RamDisk::new(&mut blocking));
+            let mut root = volume.root_dir();
+            root.create_dir("logs").unwrap();
+            let mut logs = root.cd("logs").unwrap();
+            logs.create_file("a long log name.txt").unwrap();
+            let mut file = logs.open_file("a long log name.txt").unwrap();
+            file.write(&pattern(30_000, 1), WriteType::OverWritten).unwrap();
+            file.write(&pattern(5000, 2), WriteType::Append).unwrap();
+            root.create_file("gone.txt").unwrap();
+            root.delete_file("gone.txt").unwrap();
+            root.create_dir("tmp").unwrap();
+            let mut tmp = root.cd("tmp").unwrap();
+            tmp.create_dir("deep").unwrap();
+            tmp.cd("deep").unwrap().create_file("deep file.bin").unwrap();
+            root.delete_dir("tmp").unwrap();
+            assert_eq!(root.create_file_with_size("data.bin", 10_000, true), Ok(true));
+            root.open_file("data.bin").unwrap().reserve(20_000, false).unwrap();
+            root.replace_file("data.bin", |temp| temp.write(&pattern(3000, 3), WriteType::OverWritten)).unwrap();
+            drop(file);
+            volume.unmount().unwrap();
+        }
+
+        let device = AsyncDisk { disk: RamDisk::new(&mut data), yields: Cell::new(0) };
+        let volume = run(Volume::new_async(device));
+        run(async {
+            let mut root = volume.root_dir();
+            root.create_dir_async("logs").await.unwrap();
+            assert_eq!(root.create_dir_async("logs").await, Err(DirError::DirHasExist));
+            let mut logs = root.cd_async("logs").await.unwrap();
+            logs.create_file_async("a long log name.txt").await.unwrap();
+            let mut file = logs.open_file_async("a long log name.txt").await.unwrap();
+            file.write_async(&pattern(30_000, 1), WriteType::OverWritten).await.unwrap();
+            file.write_async(&pattern(5000, 2), WriteType::Append).await.unwrap();
+            root.create_file_async("gone.txt").await.unwrap();
+            root.delete_file_async("gone.txt").await.unwrap();
+            assert!(root.exist_async("gone.txt").await.is_none());
+            root.create_dir_async("tmp").await.unwrap();
+            let mut tmp = root.cd_async("tmp").await.unwrap();
+            tmp.create_dir_async("deep").await.unwrap();
+            tmp.cd_async("deep").await.unwrap().create_file_async("deep file.bin").await.unwrap();
+            root.delete_dir_async("tmp").await.unwrap();
+            assert_eq!(root.create_file_with_size_async("data.bin", 10_000, true).await, Ok(true));
+            root.open_file_async("data.bin").await.unwrap().reserve_async(20_000, false).await.unwrap();
+            root.replace_file_async("data.bin", async |temp| temp.write_async(&pattern(3000, 3), WriteType::OverWritten).await)
+                .await.unwrap();
+
+            let mut buf = vec![0; 35_000];
+            let file = logs.open_file_async("a long log name.txt").await.unwrap();
+            assert_eq!(file.read_async(&mut buf).await.unwrap(), 35_000);
+            assert_eq!(&buf[0..30_000], &pattern(30_000, 1)[..]);
+            assert_eq!(&buf[30_000..], &pattern(5000, 2)[..]);
+            let mut buf = vec![0; 3000];
+            assert_eq!(root.open_file_async("data.bin").await.unwrap().read_async(&mut buf).await, Ok(3000));
+            assert_eq!(buf, pattern(3000, 3));
+        });
+        let device = run(volume.unmount_async()).ok().unwrap();
+        assert!(device.yields.get() > 0);
+
+        // the same on-disk logic wrote the same bytes
+        assert!(data == blocking);
+        let volume = Volume::new(RamDisk::new(&mut data));
+        assert!(volume.was_cleanly_unmounted());
+        let mut bitmap = vec![0; bitmap_size(&volume)];
+        assert_eq!(check(&volume, &mut bitmap, |problem| panic!("{:?}", problem)).unwrap(), 0);
+    }
+}
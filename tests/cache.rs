@@ -0,0 +1,142 @@
+//! SectorCache alone over a recording device: hits, LRU eviction AND write-back order
+
+mod common;
+
+use std::cell::RefCell;
+use block_device::BlockDevice;
+use common::recorder::{
+    address_of,
+    Call,
+    Calls,
+    Recorder,
+};
+use fat32::cache::{
+    Cached,
+    SectorCache,
+};
+use fat32::ram_disk::RamDisk;
+
+/// Every sector is filled with its number
+fn numbered(sectors: usize) -> Vec<u8> {
+    (0..sectors).flat_map(|sector| vec![sector as u8; 512]).collect()
+}
+
+fn read(device: &impl BlockDevice, sector: usize) -> Vec<u8> {
+    let mut buf = vec![0; 512];
+    device.read(&mut buf, address_of(sector), 1).ok().unwrap();
+    buf
+}
+
+fn write(device: &impl BlockDevice, sector: usize, value: u8) {
+    device.write(&[value; 512], address_of(sector), 1).ok().unwrap();
+}
+
+/// Get sectors which the device read OR wrote since last call
+fn sectors(calls: &Calls, write: bool) -> Vec<usize> {
+    calls.take().iter().filter(|call| call.write == write).map(|call| call.sector).collect()
+}
+
+#[test]
+fn hit_and_eviction() {
+    let mut data = numbered(16);
+    let calls = RefCell::new(vec![]);
+    let cache = SectorCache::<2>::new();
+    let device = Cached::new(Recorder { disk: RamDisk::new(&mut data), calls: &calls }, &cache);
+
+    // re-read is served by the cache
+    assert_eq!(read(&device, 3), vec![3; 512]);
+    assert_eq!(calls.take(), vec![Call { write: false, sector: 3, blocks: 1 }]);
+    assert_eq!(read(&device, 3), vec![3; 512]);
+    assert!(calls.take().is_empty());
+
+    // 4 is used after 3 but 3 is read again, so 4 is the least recently used one
+    read(&device, 4);
+    read(&device, 3);
+    calls.take();
+    assert_eq!(read(&device, 5), vec![5; 512]);
+    assert_eq!(sectors(&calls, false), vec![5]);
+    read(&device, 3);
+    assert!(calls.take().is_empty());
+    read(&device, 4);
+    assert_eq!(sectors(&calls, false), vec![4]);
+
+    // written sector is cached without reading it
+    write(&device, 9, 0xA9);
+    assert!(calls.take().is_empty());
+    assert_eq!(read(&device, 9), vec![0xA9; 512]);
+    assert!(calls.take().is_empty());
+}
+
+#[test]
+fn write_back_order() {
+    let mut data = numbered(16);
+    let calls = RefCell::new(vec![]);
+    let cache = SectorCache::<4>::new();
+    let device = Cached::new(Recorder { disk: RamDisk::new(&mut data), calls: &calls }, &cache);
+
+    // in the order they became dirty, NOT the order of sectors
+    write(&device, 7, 0xA7);
+    write(&device, 5, 0xA5);
+    write(&device, 6, 0xA6);
+    assert_eq!(cache.dirty_count(), 3);
+    device.flush().ok().unwrap();
+    assert_eq!(sectors(&calls, true), vec![7, 5, 6]);
+    assert_eq!(cache.dirty_count(), 0);
+
+    // 7 is written again after 5 became dirty, the first write of 7 goes to device before 5
+    write(&device, 7, 0xB7);
+    write(&device, 5, 0xB5);
+    assert!(calls.take().is_empty());
+    write(&device, 7, 0xC7);
+    assert_eq!(sectors(&calls, true), vec![7, 5]);
+    device.flush().ok().unwrap();
+    assert_eq!(sectors(&calls, true), vec![7]);
+
+    // evicting a dirty line writes back the lines which became dirty before it
+    write(&device, 1, 0xA1);
+    write(&device, 2, 0xA2);
+    read(&device, 3);
+    read(&device, 4);
+    calls.take();
+    read(&device, 8);
+    assert_eq!(calls.take(), vec![
+        Call { write: true, sector: 1, blocks: 1 },
+        Call { write: false, sector: 8, blocks: 1 },
+    ]);
+    device.flush().ok().unwrap();
+    assert_eq!(sectors(&calls, true), vec![2]);
+
+    let expected: Vec<(usize, u8)> = vec![(1, 0xA1), (2, 0xA2), (5, 0xB5), (6, 0xA6), (7, 0xC7), (8, 8)];
+    for (sector, value) in expected {
+        assert_eq!(data[sector * 512..(sector + 1) * 512], [value; 512], "sector {}", sector);
+    }
+}
+
+#[test]
+fn multi_block_transfer() {
+    let mut data = numbered(16);
+    let calls = RefCell::new(vec![]);
+    let cache = SectorCache::<4>::new();
+    let device = Cached::new(Recorder { disk: RamDisk::new(&mut data), calls: &calls }, &cache);
+
+    // sector 10 is only in cache, the read of 8..12 has its new data
+    write(&device, 10, 0xAA);
+    let mut buf = vec![0; 4 * 512];
+    device.read(&mut buf, address_of(8), 4).ok().unwrap();
+    assert_eq!(calls.take(), vec![Call { write: false, sector: 8, blocks: 4 }]);
+    let firsts: Vec<u8> = buf.chunks(512).map(|sector| sector[0]).collect();
+    assert_eq!(firsts, vec![8, 9, 0xAA, 11]);
+    assert_eq!(buf[1024..1536], [0xAA; 512]);
+
+    // dirty sectors go to device before a multi-block write, which updates the cached ones
+    write(&device, 2, 0xA2);
+    device.write(&[0xBB; 3 * 512], address_of(9), 3).ok().unwrap();
+    assert_eq!(calls.take(), vec![
+        Call { write: true, sector: 10, blocks: 1 },
+        Call { write: true, sector: 2, blocks: 1 },
+        Call { write: true, sector: 9, blocks: 3 },
+    ]);
+    assert_eq!(cache.dirty_count(), 0);
+    assert_eq!(read(&device, 10), vec![0xBB; 512]);
+    assert!(calls.take().is_empty());
+}
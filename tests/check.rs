@@ -0,0 +1,463 @@
+//! Check AND repair volumes which are corrupted on purpose
+
+mod common;
+
+use common::{
+    ram_image,
+    pattern,
+    RAM_IMAGES,
+};
+use common::raw::Raw;
+use fat32::bpb::FatType;
+use fat32::volume::Volume;
+use fat32::ram_disk::RamDisk;
+use fat32::dir::DirError;
+use fat32::file::{
+    FileError,
+    WriteType,
+};
+use fat32::check::{
+    bitmap_size,
+    check,
+    repair,
+    LostChains,
+    Problem,
+    Reserved,
+    MAX_DIR_DEPTH,
+};
+
+/// Get every problem check() finds
+fn problems(image: &mut [u8]) -> Vec<Problem> {
+    let volume = Volume::new(RamDisk::new(image));
+    let mut bitmap = vec![0; bitmap_size(&volume)];
+    let mut problems = vec![];
+    let count = check(&volume, &mut bitmap, |problem| problems.push(problem)).unwrap();
+    assert_eq!(count, problems.len());
+    problems
+}
+
+/// Repair volume, Return every problem AND whether it was fixed
+fn repaired(image: &mut [u8], lost: LostChains, reserved: Reserved) -> Vec<(Problem, bool)> {
+    let volume = Volume::new(RamDisk::new(image));
+    let mut bitmap = vec![0; bitmap_size(&volume)];
+    let mut problems = vec![];
+    repair(&volume, &mut bitmap, lost, reserved, |problem, fixed| problems.push((problem, fixed))).unwrap();
+    problems
+}
+
+fn write_file(volume: &Volume<RamDisk>, name: &str, data: &[u8]) {
+    let mut root = volume.root_dir();
+    root.create_file(name).unwrap();
+    root.open_file(name).unwrap().write(data, WriteType::OverWritten).unwrap();
+}
+
+fn read_file(volume: &Volume<RamDisk>, path: &[&str], name: &str) -> Vec<u8> {
+    let mut dir = volume.root_dir();
+    for d in path { dir = dir.cd(d).unwrap(); }
+    let file = dir.open_file(name).unwrap();
+    let mut buf = vec![0; 64 * 1024];
+    let length = file.read(&mut buf).unwrap();
+    buf.truncate(length);
+    buf
+}
+
+#[test]
+fn deep_dirs() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let mut image = ram_image(*fat_type, *total_sector, *spc);
+        let path = vec!["d"; MAX_DIR_DEPTH];
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            let mut dir = volume.root_dir();
+            for d in path.iter() {
+                dir.create_dir(d).unwrap();
+                dir = dir.cd(d).unwrap();
+            }
+            dir.create_file("DEEP.BIN").unwrap();
+            dir.open_file("DEEP.BIN").unwrap().write(&pattern(3000, 1), WriteType::OverWritten).unwrap();
+        }
+
+        // the deepest dir is not checked, its chain isn't taken as lost
+        let found = problems(&mut image);
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert!(matches!(found[0], Problem::DirTooDeep { entry } if entry.name == *b"D          "));
+
+        let fixed = repaired(&mut image, LostChains::Free, Reserved::Free);
+        assert!(matches!(fixed[..], [(Problem::DirTooDeep { .. }, false)]));
+        let volume = Volume::new(RamDisk::new(&mut image));
+        assert_eq!(read_file(&volume, &path, "DEEP.BIN"), pattern(3000, 1));
+    }
+}
+
+#[test]
+fn dir_loop() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let mut image = ram_image(*fat_type, *total_sector, *spc);
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            let mut root = volume.root_dir();
+            root.create_dir("a").unwrap();
+            let mut a = root.cd("a").unwrap();
+            a.create_dir("b").unwrap();
+            a.cd("b").unwrap().create_dir("c").unwrap();
+        }
+
+        // c points back to a
+        let raw = Raw::new(&image);
+        let a = raw.cluster_of(&image, raw.root_cluster, b"A          ");
+        let b = raw.cluster_of(&image, a, b"B          ");
+        let c = raw.item(&image, b, b"C          ");
+        let lost = raw.item_cluster(&image, c);
+        raw.set_item_cluster(&mut image, c, a);
+
+        let found = problems(&mut image);
+        assert!(found.iter().any(|problem| matches!(problem,
+            Problem::CrossLinked { previous: 0, cluster, .. } if *cluster == a)), "{:?}", found);
+        assert!(found.contains(&Problem::LostChain { cluster: lost, length: 1 }), "{:?}", found);
+    }
+}
+
+/// Cluster number past the last cluster of every test image, but not an end OR bad mark
+fn out_of_range(fat_type: FatType) -> u32 {
+    match fat_type {
+        FatType::FAT12 => 0xFF0,
+        FatType::FAT16 => 0xFFF0,
+        FatType::FAT32 => 0x0FFFFFF0,
+    }
+}
+
+/// Volume with KEEP.BIN AND LOST.BIN of 3 clusters each, item of LOST.BIN is deleted by hand
+fn lost_chain_image(fat_type: FatType, total_sector: u32, spc: u8) -> (Vec<u8>, usize, u32) {
+    let mut image = ram_image(fat_type, total_sector, spc);
+    let length = spc as usize * 512 * 3 - 100;
+    {
+        let volume = Volume::new(RamDisk::new(&mut image));
+        write_file(&volume, "keep.bin", &pattern(length, 1));
+        write_file(&volume, "lost.bin", &pattern(length, 2));
+    }
+    let raw = Raw::new(&image);
+    let item = raw.item(&image, raw.root_cluster, b"LOST    BIN");
+    let first = raw.item_cluster(&image, item);
+    image[item] = 0xE5;
+    (image, length, first)
+}
+
+#[test]
+fn repair_lost_chain_free() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let (mut image, length, first) = lost_chain_image(*fat_type, *total_sector, *spc);
+        assert_eq!(problems(&mut image), vec![Problem::LostChain { cluster: first, length: 3 }]);
+
+        let fixed = repaired(&mut image, LostChains::Free, Reserved::Free);
+        assert_eq!(fixed, vec![(Problem::LostChain { cluster: first, length: 3 }, true)]);
+        assert_eq!(problems(&mut image), vec![]);
+
+        let raw = Raw::new(&image);
+        assert_eq!(raw.fat(&image, first), 0);
+        let volume = Volume::new(RamDisk::new(&mut image));
+        assert_eq!(read_file(&volume, &[], "keep.bin"), pattern(length, 1));
+        assert!(volume.root_dir().exist("found.000").is_none());
+    }
+}
+
+#[test]
+fn repair_lost_chain_recover() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let (mut image, length, first) = lost_chain_image(*fat_type, *total_sector, *spc);
+        // the second lost chain is a loop of one cluster
+        let loop_cluster = {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            write_file(&volume, "loop.bin", &pattern(100, 3));
+            let raw = Raw::new(&image);
+            let item = raw.item(&image, raw.root_cluster, b"LOOP    BIN");
+            let cluster = raw.item_cluster(&image, item);
+            image[item] = 0xE5;
+            raw.set_fat(&mut image, cluster, cluster);
+            cluster
+        };
+
+        let fixed = repaired(&mut image, LostChains::Recover, Reserved::Free);
+        assert_eq!(fixed, vec![
+            (Problem::LostChain { cluster: first, length: 3 }, true),
+            (Problem::LostChain { cluster: loop_cluster, length: 1 }, true),
+        ]);
+        assert_eq!(problems(&mut image), vec![]);
+
+        let cluster_size = *spc as usize * 512;
+        let volume = Volume::new(RamDisk::new(&mut image));
+        assert_eq!(read_file(&volume, &[], "keep.bin"), pattern(length, 1));
+        let recovered = read_file(&volume, &["found.000"], "file0000.chk");
+        assert_eq!(recovered.len(), cluster_size * 3);
+        assert_eq!(recovered[0..length], pattern(length, 2)[..]);
+        let recovered = read_file(&volume, &["found.000"], "file0001.chk");
+        assert_eq!(recovered.len(), cluster_size);
+        assert_eq!(recovered[0..100], pattern(100, 3)[..]);
+    }
+}
+
+#[test]
+fn repair_cross_linked() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let mut image = ram_image(*fat_type, *total_sector, *spc);
+        let cluster_size = *spc as usize * 512;
+        let length = cluster_size * 3 - 100;
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            write_file(&volume, "a.bin", &pattern(length, 1));
+            write_file(&volume, "b.bin", &pattern(length, 2));
+        }
+
+        // the first cluster of b.bin runs into the second one of a.bin
+        let raw = Raw::new(&image);
+        let a = raw.cluster_of(&image, raw.root_cluster, b"A       BIN");
+        let b = raw.cluster_of(&image, raw.root_cluster, b"B       BIN");
+        let a2 = raw.fat(&image, a);
+        let b2 = raw.fat(&image, b);
+        raw.set_fat(&mut image, b, a2);
+
+        let fixed = repaired(&mut image, LostChains::Free, Reserved::Free);
+        let entry = match fixed[0] {
+            (Problem::CrossLinked { entry, previous, cluster }, true) if previous == b && cluster == a2 => entry,
+            _ => panic!("{:?}", fixed),
+        };
+        assert_eq!(entry.name, *b"B       BIN");
+        assert_eq!(fixed[1..], [
+            (Problem::SizeMismatch { entry, length: length as u32, clusters: 1 }, true),
+            (Problem::LostChain { cluster: b2, length: 2 }, true),
+        ]);
+        assert_eq!(problems(&mut image), vec![]);
+
+        let volume = Volume::new(RamDisk::new(&mut image));
+        assert_eq!(read_file(&volume, &[], "a.bin"), pattern(length, 1));
+        assert_eq!(read_file(&volume, &[], "b.bin"), pattern(length, 2)[0..cluster_size]);
+    }
+}
+
+#[test]
+fn repair_invalid_cluster() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let mut image = ram_image(*fat_type, *total_sector, *spc);
+        let cluster_size = *spc as usize * 512;
+        let length = cluster_size * 3 - 100;
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            write_file(&volume, "data.bin", &pattern(length, 1));
+        }
+
+        let raw = Raw::new(&image);
+        let first = raw.cluster_of(&image, raw.root_cluster, b"DATA    BIN");
+        let second = raw.fat(&image, first);
+        let third = raw.fat(&image, second);
+        raw.set_fat(&mut image, second, out_of_range(*fat_type));
+
+        let fixed = repaired(&mut image, LostChains::Free, Reserved::Free);
+        let entry = match fixed[0] {
+            (Problem::InvalidCluster { entry, previous, cluster }, true)
+                if previous == second && cluster == out_of_range(*fat_type) => entry,
+            _ => panic!("{:?}", fixed),
+        };
+        assert_eq!(fixed[1..], [
+            (Problem::SizeMismatch { entry, length: length as u32, clusters: 2 }, true),
+            (Problem::LostChain { cluster: third, length: 1 }, true),
+        ]);
+        assert_eq!(problems(&mut image), vec![]);
+
+        let volume = Volume::new(RamDisk::new(&mut image));
+        assert_eq!(read_file(&volume, &[], "data.bin"), pattern(length, 1)[0..cluster_size * 2]);
+    }
+}
+
+#[test]
+fn repair_lfn_checksum() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let mut image = ram_image(*fat_type, *total_sector, *spc);
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            write_file(&volume, "A Long File Name.txt", &pattern(3000, 1));
+            write_file(&volume, "keep.bin", &pattern(3000, 2));
+        }
+
+        // long name items come right before the short name item
+        let raw = Raw::new(&image);
+        let sfn = raw.item(&image, raw.root_cluster, b"unsupported");
+        let first = raw.item_cluster(&image, sfn);
+        image[sfn - 32 + 13] ^= 0xFF;
+
+        let fixed = repaired(&mut image, LostChains::Free, Reserved::Free);
+        assert!(matches!(fixed[..], [(Problem::LfnChecksum { entry, count: 2 }, true)] if entry.name == *b"unsupported"),
+                "{:?}", fixed);
+        assert_eq!(problems(&mut image), vec![]);
+
+        // short name item AND its chain stay
+        let raw = Raw::new(&image);
+        assert_eq!(image[sfn - 32], 0xE5);
+        assert_eq!(image[sfn - 64], 0xE5);
+        assert_eq!(raw.item_cluster(&image, sfn), first);
+        let offset = raw.cluster_offset(first);
+        assert_eq!(image[offset..offset + 512], pattern(3000, 1)[0..512]);
+        let volume = Volume::new(RamDisk::new(&mut image));
+        assert!(volume.root_dir().exist("A Long File Name.txt").is_none());
+        assert_eq!(read_file(&volume, &[], "keep.bin"), pattern(3000, 2));
+    }
+}
+
+
+/// Get name, length AND count of clusters of every ExtraClusters problem, nothing else is expected
+fn extra_clusters(problems: &[Problem]) -> Vec<([u8; 11], u32, u32)> {
+    problems.iter().map(|problem| match problem {
+        Problem::ExtraClusters { entry, length, clusters } => (entry.name, *length, *clusters),
+        _ => panic!("{:?}", problem),
+    }).collect()
+}
+
+#[test]
+fn reserved_clusters() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let mut image = ram_image(*fat_type, *total_sector, *spc);
+        let cluster_size = *spc as usize * 512;
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            let mut root = volume.root_dir();
+            assert_eq!(root.create_file_with_size("cont.bin", cluster_size * 5, true), Ok(true));
+            root.create_file("frag.bin").unwrap();
+            root.create_file("block.bin").unwrap();
+        }
+        let raw = Raw::new(&image);
+        assert_eq!(extra_clusters(&problems(&mut image)), vec![(*b"CONT    BIN", 0, 5)]);
+
+        let cont = raw.cluster_of(&image, raw.root_cluster, b"CONT    BIN");
+        assert_eq!(raw.chain(&image, cont), (cont..cont + 5).collect::<Vec<_>>());
+
+        // the cluster after frag.bin is used by block.bin
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            let mut file = volume.root_dir().open_file("frag.bin").unwrap();
+            assert_eq!(file.reserve(cluster_size * 4, true), Err(FileError::NotContiguous));
+            assert_eq!(file.reserve(cluster_size * 4, false), Ok(false));
+        }
+        let reserved = problems(&mut image);
+        assert_eq!(extra_clusters(&reserved), vec![(*b"CONT    BIN", 0, 5), (*b"FRAG    BIN", 0, 4)]);
+        let frag = raw.cluster_of(&image, raw.root_cluster, b"FRAG    BIN");
+        assert_eq!(raw.chain(&image, frag).len(), 4);
+
+        // repair reports the reserved clusters AND keeps them, Append writes into them
+        let kept = reserved.iter().map(|problem| (*problem, false)).collect::<Vec<_>>();
+        assert_eq!(repaired(&mut image, LostChains::Free, Reserved::Keep), kept);
+        let length = cluster_size * 3 + 10;
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            let root = volume.root_dir();
+            root.open_file("cont.bin").unwrap().write(&pattern(length, 1), WriteType::Append).unwrap();
+            root.open_file("frag.bin").unwrap().write(&pattern(length, 2), WriteType::Append).unwrap();
+            assert_eq!(read_file(&volume, &[], "cont.bin"), pattern(length, 1));
+            assert_eq!(read_file(&volume, &[], "frag.bin"), pattern(length, 2));
+        }
+        let reserved = problems(&mut image);
+        assert_eq!(extra_clusters(&reserved), vec![(*b"CONT    BIN", length as u32, 5)]);
+        assert_eq!(raw.chain(&image, cont), (cont..cont + 5).collect::<Vec<_>>());
+        assert_eq!(raw.chain(&image, frag).len(), 4);
+
+        // the chain is truncated to the length like 'fsck.fat -a' does
+        assert_eq!(repaired(&mut image, LostChains::Free, Reserved::Free), vec![(reserved[0], true)]);
+        assert_eq!(problems(&mut image), vec![]);
+        assert_eq!(raw.chain(&image, cont), (cont..cont + 4).collect::<Vec<_>>());
+        assert_eq!(raw.fat(&image, cont + 4), 0);
+        let volume = Volume::new(RamDisk::new(&mut image));
+        assert_eq!(read_file(&volume, &[], "cont.bin"), pattern(length, 1));
+    }
+}
+
+#[test]
+fn empty_file_without_cluster() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let mut image = ram_image(*fat_type, *total_sector, *spc);
+        let cluster_size = *spc as usize * 512;
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            let mut root = volume.root_dir();
+            root.create_file("log.bin").unwrap();
+            root.create_file("cont.bin").unwrap();
+        }
+        // other implementations write empty files with first cluster 0
+        let raw = Raw::new(&image);
+        for name in [b"LOG     BIN", b"CONT    BIN"] {
+            let item = raw.item(&image, raw.root_cluster, name);
+            let cluster = raw.item_cluster(&image, item);
+            raw.set_fat(&mut image, cluster, 0);
+            raw.set_item_cluster(&mut image, item, 0);
+        }
+        assert_eq!(problems(&mut image), vec![]);
+
+        let length = cluster_size * 2 + 10;
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            let root = volume.root_dir();
+            let handle = root.open_file("log.bin").unwrap().handle();
+            let mut file = volume.file(&handle).unwrap();
+            file.write(&pattern(length, 1), WriteType::Append).unwrap();
+            file.write(&pattern(100, 2), WriteType::Append).unwrap();
+            let mut file = root.open_file("cont.bin").unwrap();
+            assert_eq!(file.reserve(cluster_size * 3, true), Ok(true));
+            file.write(&pattern(length, 3), WriteType::Append).unwrap();
+        }
+        let mut expected = pattern(length, 1);
+        expected.extend_from_slice(&pattern(100, 2));
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            assert_eq!(read_file(&volume, &[], "log.bin"), expected);
+            assert_eq!(read_file(&volume, &[], "cont.bin"), pattern(length, 3));
+        }
+        assert_eq!(problems(&mut image), vec![]);
+        let cont = raw.cluster_of(&image, raw.root_cluster, b"CONT    BIN");
+        assert_eq!(raw.chain(&image, cont), (cont..cont + 3).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn full_volume_full_dir() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let mut image = ram_image(*fat_type, *total_sector, *spc);
+        let cluster_size = *spc as usize * 512;
+        // 2 items are '.' AND '..', one slot is left at the end of the cluster
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            let mut root = volume.root_dir();
+            root.create_dir("logs").unwrap();
+            root.create_file("fill.bin").unwrap();
+            let mut logs = root.cd("logs").unwrap();
+            for i in 0..cluster_size / 32 - 3 {
+                logs.create_file(&format!("{}.txt", i)).unwrap();
+            }
+        }
+        // fill.bin takes every free cluster but one
+        let raw = Raw::new(&image);
+        let free = raw.free_clusters(&image);
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            let file = volume.root_dir().open_file("fill.bin");
+            file.unwrap().write(&pattern(free * cluster_size, 1), WriteType::Append).unwrap();
+        }
+        assert_eq!(raw.free_clusters(&image), 1);
+        assert_eq!(problems(&mut image), vec![]);
+
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            let mut logs = volume.root_dir().cd("logs").unwrap();
+            // the file gets the last cluster, the dir can't grow for the short name item
+            assert_eq!(logs.create_file("Long Name.txt"), Err(DirError::VolumeFull));
+            assert_eq!(logs.create_dir("Long Dir"), Err(DirError::VolumeFull));
+            assert!(logs.exist("Long Name.txt").is_none());
+        }
+        assert_eq!(raw.free_clusters(&image), 1);
+        assert_eq!(problems(&mut image), vec![]);
+
+        {
+            let volume = Volume::new(RamDisk::new(&mut image));
+            let mut logs = volume.root_dir().cd("logs").unwrap();
+            logs.create_file("last.txt").unwrap();
+            assert_eq!(logs.create_file("more.txt"), Err(DirError::VolumeFull));
+        }
+        assert_eq!(raw.free_clusters(&image), 0);
+        assert_eq!(problems(&mut image), vec![]);
+    }
+}
@@ -0,0 +1,107 @@
+//! Device which fails OR loses power after some block writes
+
+use std::cell::Cell;
+use block_device::BlockDevice;
+
+const BYTE_PER_SECTOR: usize = 512;
+
+/// What happens to the block write which reaches the cut point
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Fault {
+    /// The write returns error, device keeps working
+    Fail,
+    /// Power is lost, the block is not written AND every later access fails
+    PowerLoss,
+    /// Power is lost in the middle of the block, only its first bytes are written
+    Torn(usize),
+}
+
+#[derive(Debug)]
+pub enum FaultError<E> {
+    Injected,
+    Device(E),
+}
+
+/// Cut point AND block writes counted, shared by every copy of Faulty
+pub struct Faults {
+    cut: Option<usize>,
+    fault: Fault,
+    written: Cell<usize>,
+    dead: Cell<bool>,
+}
+
+impl Faults {
+    /// Count block writes, never fail
+    pub fn none() -> Faults {
+        Faults { cut: None, fault: Fault::PowerLoss, written: Cell::new(0), dead: Cell::new(false) }
+    }
+
+    /// Fault happens to block write number cut, counted from 0
+    pub fn at(cut: usize, fault: Fault) -> Faults {
+        Faults { cut: Some(cut), fault, written: Cell::new(0), dead: Cell::new(false) }
+    }
+
+    /// Get count of blocks written
+    pub fn written(&self) -> usize {
+        self.written.get()
+    }
+}
+
+/// Wrap device, block writes go one by one so a multi-block write can be cut in the middle
+#[derive(Copy, Clone)]
+pub struct Faulty<'a, T> {
+    device: T,
+    faults: &'a Faults,
+}
+
+impl<'a, T> Faulty<'a, T> {
+    pub fn new(device: T, faults: &'a Faults) -> Self {
+        Faulty { device, faults }
+    }
+}
+
+/// Get address of the block after address
+fn next_address(address: usize) -> usize {
+    if cfg!(feature = "lba") { address + 1 } else { address + BYTE_PER_SECTOR }
+}
+
+impl<'a, T> BlockDevice for Faulty<'a, T>
+    where T: BlockDevice {
+    type Error = FaultError<T::Error>;
+
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        if self.faults.dead.get() { return Err(FaultError::Injected); }
+        self.device.read(buf, address, number_of_blocks).map_err(FaultError::Device)
+    }
+
+    fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        let mut address = address;
+        for block in buf.chunks(BYTE_PER_SECTOR).take(number_of_blocks) {
+            // nothing reaches the device after power is lost
+            if self.faults.dead.get() { return Err(FaultError::Injected); }
+            if self.faults.cut == Some(self.faults.written.get()) {
+                self.faults.dead.set(true);
+                match self.faults.fault {
+                    Fault::Fail => {
+                        // device keeps working after the error
+                        self.faults.dead.set(false);
+                        self.faults.written.set(self.faults.written.get() + 1);
+                        return Err(FaultError::Injected);
+                    }
+                    Fault::PowerLoss => return Ok(()),
+                    Fault::Torn(bytes) => {
+                        let mut torn = [0; BYTE_PER_SECTOR];
+                        self.device.read(&mut torn, address, 1).map_err(FaultError::Device)?;
+                        torn[0..bytes].copy_from_slice(&block[0..bytes]);
+                        self.device.write(&torn, address, 1).map_err(FaultError::Device)?;
+                        return Ok(());
+                    }
+                }
+            }
+            self.device.write(block, address, 1).map_err(FaultError::Device)?;
+            self.faults.written.set(self.faults.written.get() + 1);
+            address = next_address(address);
+        }
+        Ok(())
+    }
+}
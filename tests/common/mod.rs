@@ -0,0 +1,171 @@
+//! Disk images for the integration tests, formatted without any external tool
+#![allow(dead_code)]
+
+pub mod fault;
+pub mod raw;
+pub mod recorder;
+
+use std::fs::{
+    self,
+    File,
+    OpenOptions,
+};
+use std::io::{
+    Seek,
+    SeekFrom,
+    Write,
+};
+use std::path::PathBuf;
+use fat32::bpb::FatType;
+
+const BYTE_PER_SECTOR: u32 = 512;
+
+/// FSInfo of FAT32 follows the boot sector
+pub const FS_INFO_SECTOR: u32 = 1;
+
+/// Blank Volume In A Temporary Image File, Removed On Drop
+pub struct Image {
+    pub file: File,
+    path: PathBuf,
+}
+
+impl Image {
+    /// Format a blank volume in the image
+    pub fn new(name: &str, fat_type: FatType, total_sector: u32, sector_per_cluster: u8) -> Image {
+        let path = std::env::temp_dir()
+            .join(format!("fat32-{}-{}.img", std::process::id(), name));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(total_sector as u64 * BYTE_PER_SECTOR as u64).unwrap();
+
+        let mut image = Image { file, path };
+        format(fat_type, total_sector, sector_per_cluster, |sector, buf| image.write_at(sector, buf));
+        image
+    }
+
+    fn write_at(&mut self, sector: u32, buf: &[u8]) {
+        self.file.seek(SeekFrom::Start(sector as u64 * BYTE_PER_SECTOR as u64)).unwrap();
+        self.file.write_all(buf).unwrap();
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Format a blank volume in memory
+pub fn ram_image(fat_type: FatType, total_sector: u32, sector_per_cluster: u8) -> Vec<u8> {
+    let mut data = vec![0; (total_sector * BYTE_PER_SECTOR) as usize];
+    format(fat_type, total_sector, sector_per_cluster, |sector, buf| {
+        let start = (sector * BYTE_PER_SECTOR) as usize;
+        data[start..start + buf.len()].copy_from_slice(buf);
+    });
+    data
+}
+
+/// Write the boot sector AND the first FAT entries of a zeroed volume by write_at
+///
+/// FAT type comes from the count of clusters, so total_sector AND spc must fit fat_type
+fn format<F>(fat_type: FatType, total_sector: u32, spc: u8, mut write_at: F)
+    where F: FnMut(u32, &[u8]) {
+    let (reserved, root_entry_count, bits) = match fat_type {
+        FatType::FAT12 => (1, 224u32, 12),
+        FatType::FAT16 => (1, 512, 16),
+        FatType::FAT32 => (32, 0, 32),
+    };
+    let num_fat = 2;
+    let root_sectors = (root_entry_count * 32).div_ceil(BYTE_PER_SECTOR);
+
+    // FAT grows until it holds every cluster of the rest
+    let mut sector_per_fat = 1;
+    loop {
+        let clusters = (total_sector - reserved - root_sectors - num_fat * sector_per_fat) / spc as u32;
+        let needed = ((clusters + 2) * bits).div_ceil(8).div_ceil(BYTE_PER_SECTOR);
+        if needed <= sector_per_fat { break; }
+        sector_per_fat = needed;
+    }
+
+    let mut boot = [0; 512];
+    boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"MSWIN4.1");
+    boot[0x0B..0x0D].copy_from_slice(&(BYTE_PER_SECTOR as u16).to_le_bytes());
+    boot[0x0D] = spc;
+    boot[0x0E..0x10].copy_from_slice(&(reserved as u16).to_le_bytes());
+    boot[0x10] = num_fat as u8;
+    boot[0x11..0x13].copy_from_slice(&(root_entry_count as u16).to_le_bytes());
+    if total_sector < 0x10000 && fat_type != FatType::FAT32 {
+        boot[0x13..0x15].copy_from_slice(&(total_sector as u16).to_le_bytes());
+    } else {
+        boot[0x20..0x24].copy_from_slice(&total_sector.to_le_bytes());
+    }
+    boot[0x15] = 0xF8;
+    let label = match fat_type {
+        FatType::FAT32 => {
+            boot[0x24..0x28].copy_from_slice(&sector_per_fat.to_le_bytes());
+            // root dir is cluster 2
+            boot[0x2C..0x30].copy_from_slice(&2u32.to_le_bytes());
+            boot[0x30..0x32].copy_from_slice(&(FS_INFO_SECTOR as u16).to_le_bytes());
+            0x42
+        }
+        _ => {
+            boot[0x16..0x18].copy_from_slice(&(sector_per_fat as u16).to_le_bytes());
+            0x26
+        }
+    };
+    boot[label] = 0x29;
+    boot[label + 1..label + 5].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+    boot[label + 5..label + 16].copy_from_slice(b"TEST VOLUME");
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+    write_at(0, &boot);
+
+    if fat_type == FatType::FAT32 {
+        let clusters = (total_sector - reserved - num_fat * sector_per_fat) / spc as u32;
+        let mut fs_info = [0; 512];
+        fs_info[0..4].copy_from_slice(&0x41615252u32.to_le_bytes());
+        fs_info[484..488].copy_from_slice(&0x61417272u32.to_le_bytes());
+        // root dir uses cluster 2
+        fs_info[488..492].copy_from_slice(&(clusters - 1).to_le_bytes());
+        fs_info[492..496].copy_from_slice(&3u32.to_le_bytes());
+        fs_info[510] = 0x55;
+        fs_info[511] = 0xAA;
+        write_at(FS_INFO_SECTOR, &fs_info);
+    }
+
+    let first_entries: &[u8] = match fat_type {
+        FatType::FAT12 => &[0xF8, 0xFF, 0xFF],
+        FatType::FAT16 => &[0xF8, 0xFF, 0xFF, 0xFF],
+        // cluster 2 is the end of root dir
+        FatType::FAT32 => &[0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F],
+    };
+    for i in 0..num_fat {
+        write_at(reserved + i * sector_per_fat, first_entries);
+    }
+}
+
+/// Images of every FAT type, each with name, fat_type, total_sector AND sector_per_cluster
+pub const IMAGES: [(&str, FatType, u32, u8); 4] = [
+    ("fat12", FatType::FAT12, 4000, 1),
+    ("fat16", FatType::FAT16, 40000, 2),
+    ("fat32", FatType::FAT32, 70000, 1),
+    ("fat32-spc8", FatType::FAT32, 600000, 8),
+];
+
+/// Small images of every FAT type for RAM disks, each with fat_type, total_sector AND sector_per_cluster
+pub const RAM_IMAGES: [(FatType, u32, u8); 3] = [
+    (FatType::FAT12, 4000, 1),
+    (FatType::FAT16, 40000, 2),
+    (FatType::FAT32, 70000, 1),
+];
+
+/// Bytes which differ in every position, so misplaced sectors are noticed
+pub fn pattern(length: usize, seed: usize) -> Vec<u8> {
+    (0..length).map(|i| ((i * 7 + seed) % 251) as u8).collect()
+}
@@ -0,0 +1,145 @@
+//! Raw access to the volume of a ram image, to build corrupted volumes for the checker
+
+use std::convert::TryInto;
+
+const BYTE_PER_SECTOR: usize = 512;
+
+/// Layout Of The Volume, Read From The Boot Sector
+pub struct Raw {
+    fat_bits: usize,
+    fat1: usize,
+    sector_per_fat: usize,
+    num_fat: usize,
+    root_region: usize,
+    root_entry_count: usize,
+    first_data: usize,
+    pub cluster_size: usize,
+    pub root_cluster: u32,
+    pub max_cluster: u32,
+}
+
+impl Raw {
+    pub fn new(image: &[u8]) -> Raw {
+        let u16_at = |offset: usize| u16::from_le_bytes([image[offset], image[offset + 1]]) as usize;
+        let u32_at = |offset: usize| u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap());
+
+        let spc = image[0x0D] as usize;
+        let reserved = u16_at(0x0E);
+        let num_fat = image[0x10] as usize;
+        let root_entry_count = u16_at(0x11);
+        let total_sector = if u16_at(0x13) != 0 { u16_at(0x13) } else { u32_at(0x20) as usize };
+        let sector_per_fat = if u16_at(0x16) != 0 { u16_at(0x16) } else { u32_at(0x24) as usize };
+        let root_sectors = (root_entry_count * 32).div_ceil(BYTE_PER_SECTOR);
+        let root_region = reserved + num_fat * sector_per_fat;
+        let first_data = root_region + root_sectors;
+        let clusters = (total_sector - first_data) / spc;
+
+        let fat_bits = if clusters < 4085 { 12 } else if clusters < 65525 { 16 } else { 32 };
+        Raw {
+            fat_bits,
+            fat1: reserved * BYTE_PER_SECTOR,
+            sector_per_fat,
+            num_fat,
+            root_region: root_region * BYTE_PER_SECTOR,
+            root_entry_count,
+            first_data: first_data * BYTE_PER_SECTOR,
+            cluster_size: spc * BYTE_PER_SECTOR,
+            root_cluster: if fat_bits == 32 { u32_at(0x2C) } else { 0 },
+            max_cluster: clusters as u32 + 1,
+        }
+    }
+
+    /// Get FAT entry of cluster in FAT1, the 28 bits of FAT32 included the upper 4 bits
+    pub fn fat(&self, image: &[u8], cluster: u32) -> u32 {
+        let cluster = cluster as usize;
+        let at = self.fat1;
+        match self.fat_bits {
+            12 => {
+                let offset = at + cluster * 3 / 2;
+                let value = u16::from_le_bytes([image[offset], image[offset + 1]]) as u32;
+                if cluster.is_multiple_of(2) { value & 0xFFF } else { value >> 4 }
+            }
+            16 => u16::from_le_bytes([image[at + cluster * 2], image[at + cluster * 2 + 1]]) as u32,
+            _ => u32::from_le_bytes(image[at + cluster * 4..at + cluster * 4 + 4].try_into().unwrap()),
+        }
+    }
+
+    /// Set FAT entry of cluster in every FAT copy, value is written as it is
+    pub fn set_fat(&self, image: &mut [u8], cluster: u32, value: u32) {
+        let cluster = cluster as usize;
+        for fat in 0..self.num_fat {
+            let at = self.fat1 + fat * self.sector_per_fat * BYTE_PER_SECTOR;
+            match self.fat_bits {
+                12 => {
+                    let offset = at + cluster * 3 / 2;
+                    let old = u16::from_le_bytes([image[offset], image[offset + 1]]);
+                    let new = if cluster.is_multiple_of(2) {
+                        (old & 0xF000) | (value as u16 & 0xFFF)
+                    } else {
+                        (old & 0x000F) | ((value as u16) << 4)
+                    };
+                    image[offset..offset + 2].copy_from_slice(&new.to_le_bytes());
+                }
+                16 => image[at + cluster * 2..at + cluster * 2 + 2].copy_from_slice(&(value as u16).to_le_bytes()),
+                _ => image[at + cluster * 4..at + cluster * 4 + 4].copy_from_slice(&value.to_le_bytes()),
+            }
+        }
+    }
+
+    /// Get count of free clusters in FAT1
+    pub fn free_clusters(&self, image: &[u8]) -> usize {
+        (2..=self.max_cluster).filter(|&cluster| self.fat(image, cluster) == 0).count()
+    }
+
+    /// Get clusters of the chain from first, till the end mark
+    pub fn chain(&self, image: &[u8], first: u32) -> Vec<u32> {
+        let end = match self.fat_bits {
+            12 => 0xFF8,
+            16 => 0xFFF8,
+            _ => 0x0FFFFFF8,
+        };
+        let mut clusters = vec![first];
+        loop {
+            let next = self.fat(image, *clusters.last().unwrap()) & 0x0FFFFFFF;
+            if next >= end { return clusters; }
+            clusters.push(next);
+        }
+    }
+
+    /// Get byte offset of the cluster in image
+    pub fn cluster_offset(&self, cluster: u32) -> usize {
+        self.first_data + (cluster as usize - 2) * self.cluster_size
+    }
+
+    /// Get byte offset of the item with short name, like b"TEST    TXT", in the first cluster of dir
+    ///
+    /// dir_cluster is 0 for the fixed root dir region
+    pub fn item(&self, image: &[u8], dir_cluster: u32, name: &[u8; 11]) -> usize {
+        let (start, count) = if dir_cluster == 0 {
+            (self.root_region, self.root_entry_count)
+        } else {
+            (self.cluster_offset(dir_cluster), self.cluster_size / 32)
+        };
+        (0..count)
+            .map(|i| start + i * 32)
+            .find(|&offset| image[offset..offset + 11] == name[..])
+            .expect("item not found")
+    }
+
+    /// Get first cluster of the item at offset
+    pub fn item_cluster(&self, image: &[u8], item: usize) -> u32 {
+        (u16::from_le_bytes([image[item + 0x14], image[item + 0x15]]) as u32) << 16
+            | u16::from_le_bytes([image[item + 0x1A], image[item + 0x1B]]) as u32
+    }
+
+    /// Set first cluster of the item at offset
+    pub fn set_item_cluster(&self, image: &mut [u8], item: usize, cluster: u32) {
+        image[item + 0x14..item + 0x16].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        image[item + 0x1A..item + 0x1C].copy_from_slice(&(cluster as u16).to_le_bytes());
+    }
+
+    /// Get first cluster of the item with short name in dir
+    pub fn cluster_of(&self, image: &[u8], dir_cluster: u32, name: &[u8; 11]) -> u32 {
+        self.item_cluster(image, self.item(image, dir_cluster, name))
+    }
+}
@@ -0,0 +1,48 @@
+//! Device which records its calls, to see which sectors are read OR written
+
+use std::cell::RefCell;
+use block_device::BlockDevice;
+use fat32::ram_disk::{
+    DiskError,
+    RamDisk,
+};
+
+/// Read OR write call of the device
+#[derive(Debug, PartialEq)]
+pub struct Call {
+    pub write: bool,
+    pub sector: usize,
+    pub blocks: usize,
+}
+
+pub type Calls = RefCell<Vec<Call>>;
+
+/// Get sector of the address, it is the sector with feature lba
+pub fn sector_of(address: usize) -> usize {
+    if cfg!(feature = "lba") { address } else { address / 512 }
+}
+
+/// Get address of the sector, the other way of sector_of
+pub fn address_of(sector: usize) -> usize {
+    if cfg!(feature = "lba") { sector } else { sector * 512 }
+}
+
+/// Device which records the calls, to see how many blocks each transfer has
+pub struct Recorder<'a> {
+    pub disk: RamDisk<'a>,
+    pub calls: &'a Calls,
+}
+
+impl<'a> BlockDevice for Recorder<'a> {
+    type Error = DiskError;
+
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        self.calls.borrow_mut().push(Call { write: false, sector: sector_of(address), blocks: number_of_blocks });
+        self.disk.read(buf, address, number_of_blocks)
+    }
+
+    fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        self.calls.borrow_mut().push(Call { write: true, sector: sector_of(address), blocks: number_of_blocks });
+        self.disk.write(buf, address, number_of_blocks)
+    }
+}
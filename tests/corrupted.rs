@@ -0,0 +1,142 @@
+//! Read AND write files whose chain is corrupted on purpose, every guard of the chain walk
+
+mod common;
+
+use common::{
+    ram_image,
+    pattern,
+    RAM_IMAGES,
+};
+use common::raw::Raw;
+use fat32::bpb::FatType;
+use fat32::volume::Volume;
+use fat32::ram_disk::RamDisk;
+use fat32::file::{
+    FileError,
+    WriteType,
+};
+use fat32::check::{
+    bitmap_size,
+    check,
+};
+
+/// Volume with DATA.BIN of 3 clusters, Return image, data AND the clusters of the chain
+fn data_image(fat_type: FatType, total_sector: u32, spc: u8) -> (Vec<u8>, Vec<u8>, [u32; 3]) {
+    let mut image = ram_image(fat_type, total_sector, spc);
+    let data = pattern(spc as usize * 512 * 3 - 100, 1);
+    {
+        let volume = Volume::new(RamDisk::new(&mut image));
+        let mut root = volume.root_dir();
+        root.create_file("data.bin").unwrap();
+        root.open_file("data.bin").unwrap().write(&data, WriteType::OverWritten).unwrap();
+    }
+    let raw = Raw::new(&image);
+    let first = raw.cluster_of(&image, raw.root_cluster, b"DATA    BIN");
+    let second = raw.fat(&image, first);
+    let third = raw.fat(&image, second);
+    (image, data, [first, second, third])
+}
+
+/// Every access to the file fails with Corrupted, nothing is written
+fn assert_corrupted(image: &mut [u8], length: usize) {
+    let before = image.to_vec();
+    {
+        let volume = Volume::new(RamDisk::new(image));
+        let root = volume.root_dir();
+        let mut file = root.open_file("data.bin").unwrap();
+        let mut buf = vec![0; length];
+        assert_eq!(file.read(&mut buf), Err(FileError::Corrupted));
+        assert_eq!(file.write(b"new", WriteType::Append), Err(FileError::Corrupted));
+        assert_eq!(file.write(b"new", WriteType::OverWritten), Err(FileError::Corrupted));
+    }
+    assert!(image[..] == before[..]);
+}
+
+/// Value of FAT entry which marks the bad cluster
+fn bad_cluster(fat_type: FatType) -> u32 {
+    match fat_type {
+        FatType::FAT12 => 0xFF7,
+        FatType::FAT16 => 0xFFF7,
+        FatType::FAT32 => 0x0FFFFFF7,
+    }
+}
+
+#[test]
+fn upper_bits_of_fat32_entry() {
+    let (mut image, data, [first, second, third]) = data_image(FatType::FAT32, 70000, 1);
+    // the high 4 bits are reserved, they are not part of the cluster number
+    let raw = Raw::new(&image);
+    raw.set_fat(&mut image, first, 0xF000_0000 | second);
+    raw.set_fat(&mut image, second, 0xA000_0000 | third);
+    raw.set_fat(&mut image, third, 0xFFFF_FFFF);
+
+    {
+        let volume = Volume::new(RamDisk::new(&mut image));
+        let mut file = volume.root_dir().open_file("data.bin").unwrap();
+        let mut buf = vec![0; data.len()];
+        assert_eq!(file.read(&mut buf), Ok(data.len()));
+        assert_eq!(buf, data);
+        file.write(b"tail", WriteType::Append).unwrap();
+    }
+
+    // writes keep the high bits of the entries they change
+    assert_eq!(raw.fat(&image, first), 0xF000_0000 | second);
+    assert_eq!(raw.fat(&image, second) >> 28, 0xA);
+    assert_eq!(raw.fat(&image, third) >> 28, 0xF);
+
+    let volume = Volume::new(RamDisk::new(&mut image));
+    let mut bitmap = vec![0; bitmap_size(&volume)];
+    assert_eq!(check(&volume, &mut bitmap, |problem| panic!("{:?}", problem)), Ok(0));
+}
+
+#[test]
+fn bad_cluster_in_chain() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let (mut image, data, [_, second, _]) = data_image(*fat_type, *total_sector, *spc);
+        let raw = Raw::new(&image);
+        raw.set_fat(&mut image, second, bad_cluster(*fat_type));
+        assert_corrupted(&mut image, data.len());
+    }
+}
+
+#[test]
+fn out_of_range_cluster_in_chain() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let (mut image, data, [first, _, _]) = data_image(*fat_type, *total_sector, *spc);
+        let raw = Raw::new(&image);
+        raw.set_fat(&mut image, first, bad_cluster(*fat_type) - 7);
+        assert_corrupted(&mut image, data.len());
+    }
+}
+
+#[test]
+fn free_cluster_in_chain() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let (mut image, data, [first, _, _]) = data_image(*fat_type, *total_sector, *spc);
+        let raw = Raw::new(&image);
+        raw.set_fat(&mut image, first, 0);
+        assert_corrupted(&mut image, data.len());
+    }
+}
+
+#[test]
+fn loop_within_length() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let (mut image, data, [first, second, _]) = data_image(*fat_type, *total_sector, *spc);
+        // first -> second -> first, the file needs 3 clusters
+        let raw = Raw::new(&image);
+        raw.set_fat(&mut image, second, first);
+        assert_corrupted(&mut image, data.len());
+    }
+}
+
+#[test]
+fn loop_after_length() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let (mut image, data, [_, second, third]) = data_image(*fat_type, *total_sector, *spc);
+        // the 3 clusters hold the data, the chain goes on in a loop
+        let raw = Raw::new(&image);
+        raw.set_fat(&mut image, third, second);
+        assert_corrupted(&mut image, data.len());
+    }
+}
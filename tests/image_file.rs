@@ -0,0 +1,638 @@
+mod common;
+
+use std::io::{
+    Read,
+    Seek,
+    SeekFrom,
+    Write,
+};
+use common::{
+    Image,
+    IMAGES,
+    FS_INFO_SECTOR,
+    pattern,
+};
+use common::fault::{
+    Fault,
+    FaultError,
+    Faults,
+    Faulty,
+};
+use fat32::bpb::FatType;
+use fat32::cache::SectorCache;
+use fat32::volume::Volume;
+use fat32::handle::{
+    DirHandle,
+    FileHandle,
+    HandleError,
+};
+use fat32::image_file::ImageFile;
+use fat32::open_file::OpenFile;
+use fat32::dir::{
+    DirError,
+    REPLACE_TEMP_FILE,
+};
+use fat32::file::{
+    FileError,
+    OpenOptions,
+    WriteType,
+};
+use fat32::check::{
+    bitmap_size,
+    check,
+};
+
+/// Check the volume has no problem
+fn assert_clean(image: &Image) {
+    let volume = Volume::new(ImageFile::new(&image.file));
+    let mut bitmap = vec![0; bitmap_size(&volume)];
+    let count = check(&volume, &mut bitmap, |problem| panic!("{:?}", problem)).unwrap();
+    assert_eq!(count, 0);
+}
+
+fn read_all(volume: &Volume<ImageFile>, path: &[&str], name: &str) -> Vec<u8> {
+    let mut dir = volume.root_dir();
+    for d in path { dir = dir.cd(d).unwrap(); }
+    let file = dir.open_file(name).unwrap();
+    let mut buf = vec![0; 1024 * 1024];
+    let length = file.read(&mut buf).unwrap();
+    buf.truncate(length);
+    buf
+}
+
+#[test]
+fn mount() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("mount-{}", name), *fat_type, *total_sector, *spc);
+        let volume = Volume::new(ImageFile::new(&image.file));
+        assert_eq!(volume.fat_type(), *fat_type);
+        assert_eq!(volume.volume_label(), "TEST VOLUME");
+        assert_clean(&image);
+    }
+}
+
+#[test]
+fn create_and_read() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("create-{}", name), *fat_type, *total_sector, *spc);
+        let volume = Volume::new(ImageFile::new(&image.file));
+        let mut root = volume.root_dir();
+
+        root.create_file("empty.txt").unwrap();
+        assert_eq!(root.create_file("empty.txt"), Err(DirError::FileHasExist));
+        assert_eq!(root.create_file("bad:name"), Err(DirError::IllegalChar));
+        root.create_dir("dir").unwrap();
+        assert_eq!(root.create_dir("dir"), Err(DirError::DirHasExist));
+
+        for (i, length) in [1, 511, 512, 513, 4096, 100_000].iter().enumerate() {
+            let data = pattern(*length, i);
+            let file_name = format!("F{}.BIN", i);
+            root.create_file(&file_name).unwrap();
+            root.open_file(&file_name).unwrap().write(&data, WriteType::OverWritten).unwrap();
+            assert_eq!(read_all(&volume, &[], &file_name), data);
+        }
+        assert_eq!(read_all(&volume, &[], "empty.txt"), vec![]);
+
+        // everything is on the image, not only in this volume
+        let volume = Volume::new(ImageFile::new(&image.file));
+        assert_eq!(read_all(&volume, &[], "F5.BIN"), pattern(100_000, 5));
+        assert!(volume.root_dir().cd("dir").is_ok());
+        assert_clean(&image);
+    }
+}
+
+#[test]
+fn read_per_sector() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("per-sector-{}", name), *fat_type, *total_sector, *spc);
+        let volume = Volume::new(ImageFile::new(&image.file));
+        let mut root = volume.root_dir();
+        let data = pattern(10_000, 3);
+        root.create_file("data.bin").unwrap();
+        root.open_file("data.bin").unwrap().write(&data, WriteType::OverWritten).unwrap();
+
+        let mut read = vec![];
+        for (buf, length) in root.open_file("data.bin").unwrap().read_per_sector().unwrap() {
+            read.extend_from_slice(&buf[0..length]);
+        }
+        assert_eq!(read, data);
+    }
+}
+
+#[test]
+fn overwrite() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("overwrite-{}", name), *fat_type, *total_sector, *spc);
+        let volume = Volume::new(ImageFile::new(&image.file));
+        let mut root = volume.root_dir();
+        root.create_file("data.bin").unwrap();
+
+        // grow AND shrink
+        for (i, length) in [300_000, 1000, 70_000, 0, 5000].iter().enumerate() {
+            let data = pattern(*length, i);
+            root.open_file("data.bin").unwrap().write(&data, WriteType::OverWritten).unwrap();
+            assert_eq!(read_all(&volume, &[], "data.bin"), data);
+            assert_clean(&image);
+        }
+    }
+}
+
+#[test]
+fn append() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("append-{}", name), *fat_type, *total_sector, *spc);
+        let volume = Volume::new(ImageFile::new(&image.file));
+        let mut root = volume.root_dir();
+        root.create_file("log.txt").unwrap();
+
+        let mut expected = vec![];
+        for (i, length) in [1, 700, 511, 3000, 4096, 9000, 1, 20_000].iter().enumerate() {
+            let data = pattern(*length, i);
+            root.open_file("log.txt").unwrap().write(&data, WriteType::Append).unwrap();
+            expected.extend_from_slice(&data);
+            assert_eq!(read_all(&volume, &[], "log.txt"), expected);
+        }
+        assert_clean(&image);
+    }
+}
+
+#[test]
+fn delete() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("delete-{}", name), *fat_type, *total_sector, *spc);
+        let volume = Volume::new(ImageFile::new(&image.file));
+        let mut root = volume.root_dir();
+
+        root.create_file("a.bin").unwrap();
+        root.open_file("a.bin").unwrap().write(&pattern(50_000, 1), WriteType::OverWritten).unwrap();
+        root.create_dir("tree").unwrap();
+        let mut tree = root.cd("tree").unwrap();
+        tree.create_dir("sub").unwrap();
+        let mut sub = tree.cd("sub").unwrap();
+        sub.create_file("deep.bin").unwrap();
+        sub.open_file("deep.bin").unwrap().write(&pattern(20_000, 2), WriteType::OverWritten).unwrap();
+
+        assert_eq!(root.delete_file("tree"), Err(DirError::NoMatchFile));
+        assert_eq!(root.delete_dir("a.bin"), Err(DirError::NoMatchDir));
+        assert_eq!(root.delete_file("missing"), Err(DirError::NoMatchFile));
+
+        root.delete_file("a.bin").unwrap();
+        assert!(root.exist("a.bin").is_none());
+        // delete dir with everything in it
+        root.delete_dir("tree").unwrap();
+        assert_eq!(root.cd("tree").err(), Some(DirError::NoMatchDir));
+        assert_clean(&image);
+
+        // freed clusters are used again
+        root.create_file("b.bin").unwrap();
+        root.open_file("b.bin").unwrap().write(&pattern(60_000, 3), WriteType::OverWritten).unwrap();
+        assert_eq!(read_all(&volume, &[], "b.bin"), pattern(60_000, 3));
+        assert_clean(&image);
+    }
+}
+
+#[test]
+fn long_file_name() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("lfn-{}", name), *fat_type, *total_sector, *spc);
+        let volume = Volume::new(ImageFile::new(&image.file));
+        let mut root = volume.root_dir();
+        root.create_dir("long names").unwrap();
+        let mut dir = root.cd("long names").unwrap();
+
+        let names = [
+            "a file with a long name.txt",
+            "Rust牛逼.txt",
+            "exactly 13 ch",
+            "a name which needs five fragments of thirteen characters each.log",
+        ];
+        for (i, file_name) in names.iter().enumerate() {
+            dir.create_file(file_name).unwrap();
+            dir.open_file(file_name).unwrap().write(&pattern(1000 + i, i), WriteType::OverWritten).unwrap();
+        }
+        for (i, file_name) in names.iter().enumerate() {
+            assert_eq!(read_all(&volume, &["long names"], file_name), pattern(1000 + i, i));
+        }
+        dir.delete_file(names[1]).unwrap();
+        assert_eq!(dir.open_file(names[1]).err(), Some(DirError::NoMatchFile));
+        assert_eq!(read_all(&volume, &["long names"], names[3]), pattern(1003, 3));
+        assert_clean(&image);
+    }
+}
+
+#[test]
+fn multi_cluster_dir() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("big-dir-{}", name), *fat_type, *total_sector, *spc);
+        let volume = Volume::new(ImageFile::new(&image.file));
+        let mut root = volume.root_dir();
+        root.create_dir("many").unwrap();
+        let mut dir = root.cd("many").unwrap();
+
+        // every long name takes 3 items, so the dir spans several clusters
+        let count = 40 * *spc as usize;
+        for i in 0..count {
+            let file_name = format!("file number {:04}.txt", i);
+            dir.create_file(&file_name).unwrap();
+            dir.open_file(&file_name).unwrap().write(file_name.as_bytes(), WriteType::OverWritten).unwrap();
+        }
+        for i in (0..count).step_by(7) {
+            let file_name = format!("file number {:04}.txt", i);
+            assert_eq!(read_all(&volume, &["many"], &file_name), file_name.as_bytes());
+        }
+        assert_clean(&image);
+
+        root.delete_dir("many").unwrap();
+        assert_clean(&image);
+    }
+}
+
+#[test]
+fn replace_file() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("replace-{}", name), *fat_type, *total_sector, *spc);
+        let volume = Volume::new(ImageFile::new(&image.file));
+        let mut root = volume.root_dir();
+        root.create_file("config.txt").unwrap();
+        root.open_file("config.txt").unwrap().write(&pattern(20_000, 1), WriteType::OverWritten).unwrap();
+
+        // written in pieces
+        root.replace_file("config.txt", |file| {
+            file.write(&pattern(3000, 2)[0..1000], WriteType::Append)?;
+            file.write(&pattern(3000, 2)[1000..], WriteType::Append)
+        }).unwrap();
+        assert_eq!(read_all(&volume, &[], "config.txt"), pattern(3000, 2));
+        assert!(root.exist(REPLACE_TEMP_FILE).is_none());
+        assert_clean(&image);
+
+        // failed write keeps old content
+        assert_eq!(root.replace_file("config.txt", |file| {
+            file.write(&pattern(100, 3), WriteType::Append)?;
+            Err(FileError::WriteError)
+        }), Err(DirError::WriteError));
+        assert_eq!(read_all(&volume, &[], "config.txt"), pattern(3000, 2));
+        assert_eq!(root.replace_file("missing.txt", |_| Ok(())), Err(DirError::NoMatchFile));
+
+        // file of the user with the temporary name is kept
+        root.create_file(REPLACE_TEMP_FILE).unwrap();
+        root.open_file(REPLACE_TEMP_FILE).unwrap().write(&pattern(5000, 4), WriteType::OverWritten).unwrap();
+        assert_eq!(root.replace_file("config.txt", |_| Ok(())), Err(DirError::FileHasExist));
+        assert_eq!(root.replace_file(REPLACE_TEMP_FILE, |_| Ok(())), Err(DirError::FileHasExist));
+        assert_eq!(read_all(&volume, &[], REPLACE_TEMP_FILE), pattern(5000, 4));
+        assert_eq!(read_all(&volume, &[], "config.txt"), pattern(3000, 2));
+
+        // replace works again once it is deleted
+        root.delete_file(REPLACE_TEMP_FILE).unwrap();
+        root.replace_file("config.txt", |_| Ok(())).unwrap();
+        assert_eq!(read_all(&volume, &[], "config.txt"), vec![]);
+        assert!(root.exist(REPLACE_TEMP_FILE).is_none());
+        assert_clean(&image);
+    }
+}
+
+/// Driver which keeps the volume AND open handles together, like an RTIC resource
+struct Logger<'a> {
+    volume: Volume<'a, ImageFile<'a>>,
+    logs: DirHandle,
+    log: FileHandle,
+}
+
+impl<'a> Logger<'a> {
+    fn log(&self, line: &[u8]) {
+        self.volume.file(&self.log).unwrap().write(line, WriteType::Append).unwrap();
+    }
+}
+
+#[test]
+fn handles() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("handles-{}", name), *fat_type, *total_sector, *spc);
+        let volume = Volume::new(ImageFile::new(&image.file));
+        let mut root = volume.root_dir();
+        root.create_dir("logs").unwrap();
+        let mut logs = root.cd("logs").unwrap();
+        logs.create_file("a long log name.txt").unwrap();
+        logs.create_file("other log name.txt").unwrap();
+        let (logs, log) = (logs.handle(), logs.open_file("a long log name.txt").unwrap().handle());
+        let root = root.handle();
+
+        let logger = Logger { volume, logs, log };
+        let mut expected = vec![];
+        for i in 0..50 {
+            let line = pattern(100 + i, i);
+            logger.log(&line);
+            expected.extend_from_slice(&line);
+        }
+        assert_eq!(read_all(&logger.volume, &["logs"], "a long log name.txt"), expected);
+
+        // another handle of the same file sees the new length
+        let mut file = logger.volume.dir(&logger.logs).unwrap().open_file("a long log name.txt").unwrap();
+        file.write(&pattern(3000, 7), WriteType::OverWritten).unwrap();
+        let mut buf = vec![0; 3000];
+        assert_eq!(logger.volume.file(&logger.log).unwrap().read(&mut buf).unwrap(), 3000);
+        assert_eq!(buf, pattern(3000, 7));
+
+        let other = logger.volume.dir(&logger.logs).unwrap().open_file("other log name.txt").unwrap().handle();
+        logger.volume.dir(&logger.logs).unwrap().delete_file("a long log name.txt").unwrap();
+        assert_eq!(logger.volume.file(&logger.log).err(), Some(HandleError::Stale));
+        assert!(logger.volume.file(&other).is_ok());
+        assert_clean(&image);
+
+        logger.volume.dir(&root).unwrap().delete_dir("logs").unwrap();
+        assert_eq!(logger.volume.dir(&logger.logs).err(), Some(HandleError::Stale));
+        assert_eq!(logger.volume.file(&other).err(), Some(HandleError::Stale));
+        assert_clean(&image);
+
+        // handle of another volume
+        let other_image = Image::new(&format!("handles-other-{}", name), *fat_type, *total_sector, *spc);
+        let offset = if *fat_type == FatType::FAT32 { 0x43 } else { 0x27 };
+        (&other_image.file).seek(SeekFrom::Start(offset)).unwrap();
+        (&other_image.file).write_all(&[1, 2, 3, 4]).unwrap();
+        let other_volume = Volume::new(ImageFile::new(&other_image.file));
+        assert_eq!(other_volume.dir(&root).err(), Some(HandleError::OtherVolume));
+    }
+}
+
+#[test]
+fn clean_shutdown() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("clean-{}", name), *fat_type, *total_sector, *spc);
+        // FAT12 has no clean shutdown bit
+        let has_bit = *fat_type != FatType::FAT12;
+        let mounted_clean = || Volume::new(ImageFile::new(&image.file)).was_cleanly_unmounted();
+        assert!(mounted_clean());
+
+        // reading doesn't mark the volume dirty
+        let volume = Volume::new(ImageFile::new(&image.file));
+        let mut root = volume.root_dir();
+        assert_eq!(root.open_file("missing.txt").err(), Some(DirError::NoMatchFile));
+        assert!(mounted_clean());
+
+        root.create_file("data.bin").unwrap();
+        assert_eq!(mounted_clean(), !has_bit);
+        volume.flush().unwrap();
+        assert!(mounted_clean());
+        root.open_file("data.bin").unwrap().write(&pattern(3000, 1), WriteType::Append).unwrap();
+        assert_eq!(mounted_clean(), !has_bit);
+        // the bit is got at mount
+        assert!(volume.was_cleanly_unmounted());
+        volume.flush().unwrap();
+        assert_clean(&image);
+
+        // clean bit goes to the device after cached sectors
+        let cache = SectorCache::<4>::new();
+        let volume = Volume::cached(ImageFile::new(&image.file), &cache);
+        volume.root_dir().delete_file("data.bin").unwrap();
+        assert!(cache.dirty_count() > 0);
+        volume.flush().unwrap();
+        assert_eq!(cache.dirty_count(), 0);
+        assert!(mounted_clean());
+        assert_clean(&image);
+    }
+}
+
+/// Get free count AND next free cluster in FSInfo
+fn fs_info(image: &Image) -> (u32, u32) {
+    let mut buf = [0; 512];
+    let mut file = &image.file;
+    file.seek(SeekFrom::Start(FS_INFO_SECTOR as u64 * 512)).unwrap();
+    file.read_exact(&mut buf).unwrap();
+    let read = |offset: usize| u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]]);
+    (read(488), read(492))
+}
+
+#[test]
+fn open_files() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("open-files-{}", name), *fat_type, *total_sector, *spc);
+        let mut table = [OpenFile::default(); 3];
+        let volume = Volume::new(ImageFile::new(&image.file)).with_open_files(&mut table);
+        let mut root = volume.root_dir();
+        root.create_dir("logs").unwrap();
+        let mut logs = root.cd("logs").unwrap();
+        logs.create_dir("old").unwrap();
+        let mut old = logs.cd("old").unwrap();
+        old.create_file("a long log name.txt").unwrap();
+        for name in ["config.txt", "c.txt", "d.txt"] { root.create_file(name).unwrap(); }
+        let d = root.open_file("d.txt").unwrap().handle();
+
+        // files of the same position share a slot, they see the same length
+        let mut writer = old.open_file("a long log name.txt").unwrap();
+        let reader = writer.clone();
+        let other = volume.file(&writer.handle()).unwrap();
+        writer.write(&pattern(3000, 1), WriteType::Append).unwrap();
+        writer.write(&pattern(2000, 2), WriteType::OverWritten).unwrap();
+        let mut buf = vec![0; 2000];
+        assert_eq!(reader.read(&mut buf).unwrap(), 2000);
+        assert_eq!(buf, pattern(2000, 2));
+        writer.write(&pattern(700, 3), WriteType::Append).unwrap();
+        let mut buf = vec![0; 2700];
+        assert_eq!(other.read(&mut buf).unwrap(), 2700);
+        assert_eq!(&buf[2000..], &pattern(700, 3)[..]);
+
+        // open file AND dir which has it can't be deleted
+        assert_eq!(old.delete_file("a long log name.txt"), Err(DirError::FileIsOpen));
+        assert_eq!(root.delete_dir("logs"), Err(DirError::FileIsOpen));
+
+        // replace_file switches the file which is open
+        let config = root.open_file("config.txt").unwrap();
+        root.replace_file("config.txt", |file| file.write(&pattern(500, 4), WriteType::Append)).unwrap();
+        let mut buf = vec![0; 500];
+        assert_eq!(config.read(&mut buf).unwrap(), 500);
+        assert_eq!(buf, pattern(500, 4));
+
+        // every slot is used
+        let c = root.open_file("c.txt").unwrap();
+        assert_eq!(root.open_file("d.txt").err(), Some(DirError::TooManyOpenFiles));
+        assert_eq!(volume.file(&d).err(), Some(HandleError::TooManyOpenFiles));
+        // c.txt shares its slot, the temporary file gets none AND is not left behind
+        assert_eq!(root.replace_file("c.txt", |_| Ok(())), Err(DirError::TooManyOpenFiles));
+        assert!(root.exist(REPLACE_TEMP_FILE).is_none());
+        drop(c);
+        assert!(volume.file(&d).is_ok());
+
+        // the last file of the position frees the slot
+        drop((writer, reader));
+        assert_eq!(root.delete_dir("logs"), Err(DirError::FileIsOpen));
+        drop(other);
+        root.delete_dir("logs").unwrap();
+        assert_clean(&image);
+    }
+}
+
+#[test]
+fn open_options() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("open-options-{}", name), *fat_type, *total_sector, *spc);
+        let volume = Volume::new(ImageFile::new(&image.file));
+        let mut root = volume.root_dir();
+        root.create_dir("logs").unwrap();
+        let mut logs = root.cd("logs").unwrap();
+
+        assert_eq!(OpenOptions::new().open(&mut root, "a.txt").err(), Some(DirError::InvalidOptions));
+        assert_eq!(OpenOptions::new().read(true).truncate(true).open(&mut root, "a.txt").err(), Some(DirError::InvalidOptions));
+        assert_eq!(OpenOptions::new().read(true).create(true).open(&mut root, "a.txt").err(), Some(DirError::InvalidOptions));
+        assert_eq!(OpenOptions::new().read(true).open(&mut root, "a.txt").err(), Some(DirError::NoMatchFile));
+
+        // created AND opened at once
+        let mut file = OpenOptions::new().write(true).create(true).open(&mut logs, "a long log name.txt").unwrap();
+        file.write(&pattern(3000, 1), WriteType::OverWritten).unwrap();
+        let mut buf = vec![0; 3000];
+        assert_eq!(file.read(&mut buf), Err(FileError::NotReadable));
+        assert!(matches!(file.read_per_sector(), Err(FileError::NotReadable)));
+        // only FAT is read to get the place of the file
+        assert_eq!(file.extents().map(|(_, count)| count).sum::<usize>(), 6);
+        assert!(file.offset_sector(2999).is_some());
+        assert_eq!(read_all(&volume, &["logs"], "a long log name.txt"), pattern(3000, 1));
+
+        // every write of append is Append
+        let mut file = OpenOptions::new().read(true).append(true).open(&mut logs, "a long log name.txt").unwrap();
+        file.write(&pattern(2000, 2), WriteType::OverWritten).unwrap();
+        let mut buf = vec![0; 5000];
+        assert_eq!(file.read(&mut buf).unwrap(), 5000);
+        assert_eq!(&buf[3000..], &pattern(2000, 2)[..]);
+
+        let mut file = OpenOptions::new().read(true).open(&mut logs, "a long log name.txt").unwrap();
+        assert_eq!(file.write(&pattern(10, 3), WriteType::Append), Err(FileError::NotWritable));
+        assert_eq!(file.reserve(10_000, false), Err(FileError::NotWritable));
+
+        // truncate frees the old chain
+        let file = OpenOptions::new().read(true).write(true).truncate(true).open(&mut logs, "a long log name.txt").unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+        assert_clean(&image);
+
+        assert_eq!(OpenOptions::new().write(true).create_new(true).open(&mut logs, "a long log name.txt").err(),
+                   Some(DirError::FileHasExist));
+        assert_eq!(OpenOptions::new().write(true).create_new(true).open(&mut root, "logs").err(),
+                   Some(DirError::DirHasExist));
+        assert_eq!(OpenOptions::new().write(true).create(true).open(&mut root, "logs").err(),
+                   Some(DirError::NoMatchFile));
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&mut root, "b.txt").unwrap();
+        file.write(&pattern(700, 4), WriteType::Append).unwrap();
+        assert_eq!(read_all(&volume, &[], "b.txt"), pattern(700, 4));
+        assert_clean(&image);
+
+        // nothing is written to read-only volume
+        let volume = Volume::read_only(ImageFile::new(&image.file));
+        let mut root = volume.root_dir();
+        assert_eq!(OpenOptions::new().append(true).open(&mut root, "b.txt").err(), Some(DirError::ReadOnly));
+        assert!(OpenOptions::new().read(true).open(&mut root, "b.txt").is_ok());
+    }
+}
+
+#[test]
+fn flush_fs_info() {
+    for (name, _, total_sector, spc) in IMAGES.iter().filter(|image| image.1 == FatType::FAT32) {
+        let image = Image::new(&format!("fs-info-{}", name), FatType::FAT32, *total_sector, *spc);
+        let (free_count, next_free) = fs_info(&image);
+        assert_eq!(next_free, 3);
+
+        // flush doesn't write anything if volume wasn't modified
+        let volume = Volume::new(ImageFile::new(&image.file));
+        volume.flush().unwrap();
+        assert_eq!(fs_info(&image), (free_count, next_free));
+
+        // without bitmap, counting reads the whole FAT, they are unknown
+        volume.root_dir().create_file("a.bin").unwrap();
+        volume.flush().unwrap();
+        assert_eq!(fs_info(&image), (0xFFFFFFFF, 0xFFFFFFFF));
+
+        let volume = Volume::new(ImageFile::new(&image.file));
+        let mut bitmap = vec![0; volume.bitmap_size()];
+        let volume = volume.with_bitmap(&mut bitmap).unwrap();
+        let mut root = volume.root_dir();
+        root.create_file("b.bin").unwrap();
+        root.open_file("b.bin").unwrap().write(&pattern(10_000, 1), WriteType::OverWritten).unwrap();
+        volume.flush().unwrap();
+        let clusters = 10_000u32.div_ceil(*spc as u32 * 512);
+        // a.bin has cluster 3, OverWritten freed the first cluster of b.bin
+        assert_eq!(fs_info(&image), (free_count - 1 - clusters, 4));
+        assert_clean(&image);
+    }
+}
+
+#[test]
+fn unmount_and_sync() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter() {
+        let image = Image::new(&format!("unmount-{}", name), *fat_type, *total_sector, *spc);
+        let has_bit = *fat_type != FatType::FAT12;
+        let mounted_clean = || Volume::new(ImageFile::new(&image.file)).was_cleanly_unmounted();
+
+        let cache = SectorCache::<8>::new();
+        let volume = Volume::cached(ImageFile::new(&image.file), &cache);
+        let mut root = volume.root_dir();
+        root.create_file("log.txt").unwrap();
+        let mut file = root.open_file("log.txt").unwrap();
+        file.write(&pattern(700, 1), WriteType::Append).unwrap();
+        assert!(cache.dirty_count() > 0);
+
+        // data AND length are on the device, the volume is still dirty
+        file.sync().unwrap();
+        assert_eq!(cache.dirty_count(), 0);
+        assert_eq!(read_all(&Volume::new(ImageFile::new(&image.file)), &[], "log.txt"), pattern(700, 1));
+        assert_eq!(mounted_clean(), !has_bit);
+
+        file.write(&pattern(700, 2), WriteType::Append).unwrap();
+        drop(file);
+        let device = volume.unmount().unwrap();
+        assert_eq!(cache.dirty_count(), 0);
+        assert!(mounted_clean());
+        assert_clean(&image);
+
+        // the device is given back
+        let volume = Volume::new(device.into_inner());
+        assert_eq!(read_all(&volume, &[], "log.txt").len(), 1400);
+    }
+}
+
+#[test]
+fn flush_error() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter().filter(|image| image.1 != FatType::FAT12) {
+        let image = Image::new(&format!("flush-error-{}", name), *fat_type, *total_sector, *spc);
+        let mounted_clean = || Volume::new(ImageFile::new(&image.file)).was_cleanly_unmounted();
+
+        // count block writes of the modification on another image
+        let counted = Image::new(&format!("flush-error-count-{}", name), *fat_type, *total_sector, *spc);
+        let faults = Faults::none();
+        Volume::new(Faulty::new(ImageFile::new(&counted.file), &faults)).root_dir().create_file("a.txt").unwrap();
+
+        // the volume is clean at mount, the first write of flush fails
+        let faults = Faults::at(faults.written(), Fault::Fail);
+        let volume = Volume::new(Faulty::new(ImageFile::new(&image.file), &faults));
+        volume.root_dir().create_file("a.txt").unwrap();
+        assert!(volume.was_cleanly_unmounted());
+        assert!(matches!(volume.flush(), Err(FaultError::Injected)));
+        assert!(!mounted_clean());
+
+        // device works again
+        volume.unmount().ok().unwrap();
+        assert!(mounted_clean());
+    }
+}
+
+#[test]
+fn dirty_at_mount() {
+    for (name, fat_type, total_sector, spc) in IMAGES.iter().filter(|image| image.1 != FatType::FAT12) {
+        let image = Image::new(&format!("dirty-{}", name), *fat_type, *total_sector, *spc);
+        let mounted_clean = || Volume::new(ImageFile::new(&image.file)).was_cleanly_unmounted();
+        // power lost before flush
+        Volume::new(ImageFile::new(&image.file)).root_dir().create_file("a.txt").unwrap();
+        assert!(!mounted_clean());
+
+        // flush doesn't hide a volume which may be corrupted
+        let volume = Volume::new(ImageFile::new(&image.file));
+        volume.root_dir().create_file("b.txt").unwrap();
+        volume.flush().unwrap();
+        assert!(!mounted_clean());
+        volume.unmount().ok().unwrap();
+        assert!(!mounted_clean());
+
+        // check finds nothing, then flush marks it clean
+        let volume = Volume::new(ImageFile::new(&image.file));
+        let mut bitmap = vec![0; bitmap_size(&volume)];
+        assert_eq!(check(&volume, &mut bitmap, |problem| panic!("{:?}", problem)), Ok(0));
+        volume.unmount().ok().unwrap();
+        assert!(mounted_clean());
+        assert_clean(&image);
+    }
+}
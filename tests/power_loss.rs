@@ -0,0 +1,270 @@
+//! Cut power at every block write of an operation, then check the volume
+//!
+//! Acceptable damage is what a checker repairs without losing data which was there before:
+//! lost clusters, clusters past the file length, FAT copies which differ, long name items
+//! without their short name item. Cross-linked chains, items pointing to free clusters OR to garbage are not acceptable
+
+mod common;
+
+use std::panic::{
+    self,
+    AssertUnwindSafe,
+};
+use std::sync::Once;
+use block_device::BlockDevice;
+use common::{
+    ram_image,
+    pattern,
+    RAM_IMAGES,
+};
+use common::fault::{
+    Fault,
+    FaultError,
+    Faults,
+    Faulty,
+};
+use fat32::cache::{
+    Cached,
+    SectorCache,
+};
+use fat32::volume::Volume;
+use fat32::ram_disk::{
+    DiskError,
+    RamDisk,
+};
+use fat32::file::WriteType;
+use fat32::check::{
+    bitmap_size,
+    check,
+    Problem,
+};
+
+type Setup = fn(&Volume<RamDisk>);
+type Operation = fn(&Volume<Device>);
+type Verify = fn(&Volume<RamDisk>);
+
+/// Few sectors, so the cache evicts in the middle of an operation
+const CACHE_SECTORS: usize = 4;
+
+/// Faulty device, OR the sector cache in front of it
+enum Device<'a> {
+    Plain(Faulty<'a, RamDisk<'a>>),
+    Cached(Cached<'a, Faulty<'a, RamDisk<'a>>, CACHE_SECTORS>),
+}
+
+impl<'a> BlockDevice for Device<'a> {
+    type Error = FaultError<DiskError>;
+
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        match self {
+            Device::Plain(device) => device.read(buf, address, number_of_blocks),
+            Device::Cached(device) => device.read(buf, address, number_of_blocks),
+        }
+    }
+
+    fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        match self {
+            Device::Plain(device) => device.write(buf, address, number_of_blocks),
+            Device::Cached(device) => device.write(buf, address, number_of_blocks),
+        }
+    }
+}
+
+/// Run operation on device, cached sectors are written back after it
+fn run_on(data: &mut [u8], faults: &Faults, cached: bool, operation: Operation) {
+    let faulty = Faulty::new(RamDisk::new(data), faults);
+    if !cached { return operation(&Volume::new(Device::Plain(faulty))); }
+
+    let cache = SectorCache::<CACHE_SECTORS>::new();
+    let device = Cached::new(faulty, &cache);
+    operation(&Volume::new(Device::Cached(device)));
+    device.flush().unwrap();
+}
+
+/// Injected errors panic in the library, don't print them
+fn quiet_injected_panics() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !info.to_string().contains("Injected") { hook(info); }
+        }));
+    });
+}
+
+fn is_acceptable(problem: &Problem) -> bool {
+    matches!(problem, Problem::LostChain { .. } | Problem::ExtraClusters { .. }
+        | Problem::FatMismatch { .. } | Problem::OrphanLfn { .. })
+}
+
+/// Run operation on a copy of image, with fault at every block write it does
+fn replay(name: &str, setup: Setup, operation: Operation) {
+    replay_verify(name, setup, operation, |_| ());
+}
+
+/// Like replay, verify checks the content of the volume after every fault too
+fn replay_verify(name: &str, setup: Setup, operation: Operation, verify: Verify) {
+    quiet_injected_panics();
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter() {
+        let mut image = ram_image(*fat_type, *total_sector, *spc);
+        setup(&Volume::new(RamDisk::new(&mut image)));
+
+        // bytes after the last used one are zero, a zeroed vec is cheap to get
+        let used = image.iter().rposition(|byte| *byte != 0).unwrap() + 1;
+        let copy = || {
+            let mut data = vec![0; image.len()];
+            data[0..used].copy_from_slice(&image[0..used]);
+            data
+        };
+
+        for cached in [false, true] {
+            let mut dry_run = copy();
+            let faults = Faults::none();
+            run_on(&mut dry_run, &faults, cached, operation);
+            let writes = faults.written();
+            assert!(writes > 0);
+
+            let run = |cut, fault| {
+                let mut data = copy();
+                let faults = Faults::at(cut, fault);
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    run_on(&mut data, &faults, cached, operation);
+                }));
+                // error of device panics in library, after power loss it may go on till next access
+                if fault == Fault::Fail { assert!(result.is_err()); }
+                data
+            };
+
+            // many writes leave the image as it was, like FAT copies of unchanged sector
+            let mut checked = vec![];
+            for cut in 0..writes {
+                // nothing is written after the error, just like power loss
+                let power_loss = run(cut, Fault::PowerLoss);
+                assert!(run(cut, Fault::Fail) == power_loss);
+
+                for (fault, data) in [(Fault::PowerLoss, power_loss), (Fault::Torn(100), run(cut, Fault::Torn(100)))] {
+                    if data == checked { continue; }
+                    checked = data;
+                    let volume = Volume::new(RamDisk::new(&mut checked));
+                    let mut bitmap = vec![0; bitmap_size(&volume)];
+                    check(&volume, &mut bitmap, |problem| {
+                        assert!(is_acceptable(&problem),
+                                "{} on {:?}{}, {:?} at block write {} of {}: {:?}",
+                                name, fat_type, if cached { " cached" } else { "" }, fault, cut, writes, problem);
+                    }).unwrap();
+                    verify(&volume);
+                }
+            }
+        }
+    }
+}
+
+fn create_files(volume: &Volume<RamDisk>, count: usize) {
+    let mut root = volume.root_dir();
+    for i in 0..count { root.create_file(&format!("FILE{}.TXT", i)).unwrap(); }
+}
+
+fn create_data(volume: &Volume<RamDisk>, length: usize) {
+    let mut root = volume.root_dir();
+    root.create_file("DATA.BIN").unwrap();
+    root.open_file("DATA.BIN").unwrap().write(&pattern(length, 1), WriteType::OverWritten).unwrap();
+}
+
+#[test]
+fn create_file() {
+    replay("create file", |_| (), |volume| volume.root_dir().create_file("NEW.TXT").unwrap());
+}
+
+#[test]
+fn create_long_file_name() {
+    // items of the new name cross a sector
+    replay("create long file name",
+           |volume| create_files(volume, 15),
+           |volume| volume.root_dir().create_file("a long file name.txt").unwrap());
+}
+
+#[test]
+fn create_dir() {
+    replay("create dir",
+           |volume| volume.root_dir().create_dir("parent").unwrap(),
+           |volume| volume.root_dir().cd("parent").unwrap().create_dir("child").unwrap());
+}
+
+#[test]
+fn grow_dir() {
+    // the first cluster of dir is full, '.' AND '..' take 2 items
+    replay("grow dir",
+           |volume| {
+               let mut root = volume.root_dir();
+               root.create_dir("full").unwrap();
+               let mut full = root.cd("full").unwrap();
+               for i in 0..(16 * 2 - 2) { full.create_file(&format!("FILE{}.TXT", i)).unwrap(); }
+           },
+           |volume| volume.root_dir().cd("full").unwrap().create_file("LAST.TXT").unwrap());
+}
+
+/// File has old data OR new data, never a mix of them
+fn old_or_new(volume: &Volume<RamDisk>, old: usize, new: usize) {
+    let mut buf = vec![0; 16 * 1024];
+    let length = volume.root_dir().open_file("DATA.BIN").unwrap().read(&mut buf).unwrap();
+    let data = &buf[0..length];
+    assert!(data == &pattern(old, 1)[..] || data == &pattern(new, 2)[..]);
+}
+
+#[test]
+fn overwrite_grow() {
+    replay_verify("overwrite grow",
+                  |volume| create_data(volume, 3000),
+                  |volume| volume.root_dir().open_file("DATA.BIN").unwrap()
+                      .write(&pattern(8000, 2), WriteType::OverWritten).unwrap(),
+                  |volume| old_or_new(volume, 3000, 8000));
+}
+
+#[test]
+fn overwrite_shrink() {
+    replay_verify("overwrite shrink",
+                  |volume| create_data(volume, 8000),
+                  |volume| volume.root_dir().open_file("DATA.BIN").unwrap()
+                      .write(&pattern(1000, 2), WriteType::OverWritten).unwrap(),
+                  |volume| old_or_new(volume, 8000, 1000));
+}
+
+#[test]
+fn replace_file() {
+    replay_verify("replace file",
+                  |volume| create_data(volume, 3000),
+                  |volume| volume.root_dir().replace_file("DATA.BIN", |file| {
+                      file.write(&pattern(8000, 2)[0..5000], WriteType::Append)?;
+                      file.write(&pattern(8000, 2)[5000..], WriteType::Append)
+                  }).unwrap(),
+                  |volume| old_or_new(volume, 3000, 8000));
+}
+
+#[test]
+fn append() {
+    replay("append",
+           |volume| create_data(volume, 3000),
+           |volume| volume.root_dir().open_file("DATA.BIN").unwrap()
+               .write(&pattern(5000, 2), WriteType::Append).unwrap());
+}
+
+#[test]
+fn delete_file() {
+    replay("delete file",
+           |volume| create_data(volume, 8000),
+           |volume| volume.root_dir().delete_file("DATA.BIN").unwrap());
+}
+
+#[test]
+fn delete_dir() {
+    replay("delete dir",
+           |volume| {
+               let mut root = volume.root_dir();
+               root.create_dir("tree").unwrap();
+               let mut tree = root.cd("tree").unwrap();
+               tree.create_file("a long file name.txt").unwrap();
+               tree.open_file("a long file name.txt").unwrap().write(&pattern(5000, 3), WriteType::OverWritten).unwrap();
+               tree.create_dir("SUB").unwrap();
+           },
+           |volume| volume.root_dir().delete_dir("tree").unwrap());
+}
@@ -0,0 +1,229 @@
+mod common;
+
+use std::cell::RefCell;
+use block_device::BlockDevice;
+use common::{
+    ram_image,
+    pattern,
+    RAM_IMAGES,
+};
+use fat32::bpb::FatType;
+use fat32::volume::{
+    Volume,
+    VolumeError,
+};
+use fat32::ram_disk::{
+    DiskError,
+    RamDisk,
+    RomDisk,
+};
+use fat32::dir::DirError;
+use fat32::file::{
+    FileError,
+    WriteType,
+};
+use fat32::check::{
+    bitmap_size,
+    check,
+    repair,
+    CheckError,
+    LostChains,
+    Reserved,
+};
+
+#[test]
+fn ram_disk() {
+    for (fat_type, total_sector, spc) in RAM_IMAGES.iter().filter(|image| image.0 != FatType::FAT32) {
+        let mut data = ram_image(*fat_type, *total_sector, *spc);
+        let volume = Volume::new(RamDisk::new(&mut data));
+        let mut root = volume.root_dir();
+        root.create_dir("tmp").unwrap();
+        let mut tmp = root.cd("tmp").unwrap();
+        tmp.create_file("a long file name.bin").unwrap();
+        let mut file = tmp.open_file("a long file name.bin").unwrap();
+        file.write(&pattern(30_000, 1), WriteType::OverWritten).unwrap();
+
+        let mut buf = vec![0; 30_000];
+        assert_eq!(tmp.open_file("a long file name.bin").unwrap().read(&mut buf).unwrap(), 30_000);
+        assert_eq!(buf, pattern(30_000, 1));
+
+        let mut bitmap = vec![0; bitmap_size(&volume)];
+        assert_eq!(check(&volume, &mut bitmap, |problem| panic!("{:?}", problem)).unwrap(), 0);
+    }
+}
+
+#[test]
+fn rom_disk() {
+    let mut data = ram_image(FatType::FAT16, 40000, 2);
+    let volume = Volume::new(RamDisk::new(&mut data));
+    let mut root = volume.root_dir();
+    root.create_file("firmware.bin").unwrap();
+    root.open_file("firmware.bin").unwrap().write(&pattern(70_000, 2), WriteType::OverWritten).unwrap();
+
+    // the image is baked into flash
+    let volume = Volume::read_only(RomDisk::new(&data));
+    let mut root = volume.root_dir();
+    let mut buf = vec![0; 70_000];
+    assert_eq!(root.open_file("firmware.bin").unwrap().read(&mut buf).unwrap(), 70_000);
+    assert_eq!(buf, pattern(70_000, 2));
+    assert_eq!(root.create_file("new.txt"), Err(DirError::ReadOnly));
+}
+
+#[test]
+fn read_only_volume() {
+    let mut data = ram_image(FatType::FAT16, 40000, 2);
+    {
+        let volume = Volume::new(RamDisk::new(&mut data));
+        let mut root = volume.root_dir();
+        root.create_dir("logs").unwrap();
+        root.create_file("config.txt").unwrap();
+        root.open_file("config.txt").unwrap().write(&pattern(3000, 1), WriteType::OverWritten).unwrap();
+    }
+
+    // RomDisk can't be written, every mutating method fails before any write
+    let volume = Volume::read_only(RomDisk::new(&data));
+    let mut root = volume.root_dir();
+    assert_eq!(root.create_file("new.txt"), Err(DirError::ReadOnly));
+    assert_eq!(root.create_file_with_size("new.bin", 4096, true), Err(DirError::ReadOnly));
+    assert_eq!(root.create_dir("new"), Err(DirError::ReadOnly));
+    assert_eq!(root.delete_file("config.txt"), Err(DirError::ReadOnly));
+    assert_eq!(root.delete_dir("logs"), Err(DirError::ReadOnly));
+    assert_eq!(root.replace_file("config.txt", |_| Ok(())), Err(DirError::ReadOnly));
+
+    let mut file = root.open_file("config.txt").unwrap();
+    assert_eq!(file.write(b"new", WriteType::Append), Err(FileError::ReadOnly));
+    assert_eq!(file.write(b"new", WriteType::OverWritten), Err(FileError::ReadOnly));
+    assert_eq!(file.reserve(10_000, false), Err(FileError::ReadOnly));
+
+    let mut bitmap = vec![0; bitmap_size(&volume)];
+    assert_eq!(repair(&volume, &mut bitmap, LostChains::Free, Reserved::Free, |problem, _| panic!("{:?}", problem)),
+               Err(CheckError::ReadOnly));
+    assert_eq!(check(&volume, &mut bitmap, |problem| panic!("{:?}", problem)), Ok(0));
+    assert!(volume.flush().is_ok());
+
+    // nothing changed
+    let mut buf = vec![0; 3000];
+    assert_eq!(file.read(&mut buf), Ok(3000));
+    assert_eq!(buf, pattern(3000, 1));
+    assert!(root.cd("logs").is_ok());
+    assert!(root.exist("new.txt").is_none());
+}
+
+/// Device like an SPI driver, it owns its bus AND is neither Copy nor Clone
+struct OwnedDisk {
+    data: RefCell<Vec<u8>>,
+}
+
+impl BlockDevice for OwnedDisk {
+    type Error = DiskError;
+
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        RamDisk::new(&mut self.data.borrow_mut()).read(buf, address, number_of_blocks)
+    }
+
+    fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        RamDisk::new(&mut self.data.borrow_mut()).write(buf, address, number_of_blocks)
+    }
+}
+
+#[test]
+fn owned_device() {
+    let disk = OwnedDisk { data: RefCell::new(ram_image(FatType::FAT16, 40000, 2)) };
+    let volume = Volume::new(disk);
+    let mut root = volume.root_dir();
+    root.create_dir("logs").unwrap();
+    let mut logs = root.cd("logs").unwrap();
+    logs.create_file("boot.log").unwrap();
+    let mut file = logs.open_file("boot.log").unwrap();
+    file.write(&pattern(5000, 1), WriteType::Append).unwrap();
+
+    // handles borrow the volume, so it is given back after them
+    drop(file);
+    let disk = volume.unmount().ok().unwrap();
+    let volume = Volume::new(disk);
+    assert!(volume.was_cleanly_unmounted());
+    let mut buf = vec![0; 5000];
+    assert_eq!(volume.root_dir().cd("logs").unwrap().open_file("boot.log").unwrap().read(&mut buf).unwrap(), 5000);
+    assert_eq!(buf, pattern(5000, 1));
+
+    // the volume comes back with the error
+    let mut bitmap = [0; 1];
+    let (volume, error) = volume.with_bitmap(&mut bitmap).err().unwrap();
+    assert_eq!(error, VolumeError::BitmapTooSmall);
+    assert_eq!(volume.fat_type(), FatType::FAT16);
+}
+
+/// Device which has only the boot sector, every other sector reads as zeros
+struct BootSectorDisk {
+    boot: Vec<u8>,
+}
+
+impl BlockDevice for BootSectorDisk {
+    type Error = DiskError;
+
+    fn read(&self, buf: &mut [u8], address: usize, _number_of_blocks: usize) -> Result<(), Self::Error> {
+        buf.iter_mut().for_each(|byte| *byte = 0);
+        if address == 0 { buf[0..512].copy_from_slice(&self.boot); }
+        Ok(())
+    }
+
+    fn write(&self, _buf: &[u8], _address: usize, _number_of_blocks: usize) -> Result<(), Self::Error> {
+        Err(DiskError::OutOfRange)
+    }
+}
+
+/// Device whose every read fails
+struct FailingDisk;
+
+impl BlockDevice for FailingDisk {
+    type Error = DiskError;
+
+    fn read(&self, _buf: &mut [u8], _address: usize, _number_of_blocks: usize) -> Result<(), Self::Error> {
+        Err(DiskError::OutOfRange)
+    }
+
+    fn write(&self, _buf: &[u8], _address: usize, _number_of_blocks: usize) -> Result<(), Self::Error> {
+        Err(DiskError::OutOfRange)
+    }
+}
+
+#[test]
+fn try_new() {
+    let mut data = ram_image(FatType::FAT16, 40000, 2);
+    data[0x0B..0x0D].copy_from_slice(&1024u16.to_le_bytes());
+    let (_, error) = Volume::try_new(RamDisk::new(&mut data)).err().unwrap();
+    assert_eq!(error, VolumeError::SectorSize);
+
+    // 8 GiB volume, its byte address overflows usize of 32-bit target
+    let mut boot = ram_image(FatType::FAT32, 70000, 1)[0..512].to_vec();
+    boot[0x20..0x24].copy_from_slice(&16_000_000u32.to_le_bytes());
+    let result = Volume::try_new(BootSectorDisk { boot });
+    if cfg!(target_pointer_width = "32") && !cfg!(feature = "lba") {
+        assert_eq!(result.err().unwrap().1, VolumeError::TooLarge);
+    } else {
+        assert_eq!(result.ok().unwrap().fat_type(), FatType::FAT32);
+    }
+
+    assert_eq!(Volume::try_new(FailingDisk).err().unwrap().1, VolumeError::ReadError);
+
+    // boot sector whose geometry would divide by zero OR underflow
+    let boot = ram_image(FatType::FAT16, 40000, 2)[0..512].to_vec();
+    let patches: [(usize, &[u8]); 6] = [
+        (0x0D, &[0]),
+        (0x0D, &[3]),
+        (0x10, &[0]),
+        (0x16, &[0, 0]),
+        (0x13, &100u16.to_le_bytes()),
+        (0x0E, &40000u16.to_le_bytes()),
+    ];
+    for (offset, bytes) in patches.iter() {
+        let mut boot = boot.clone();
+        boot[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+        let result = Volume::try_new(BootSectorDisk { boot });
+        assert_eq!(result.err().unwrap().1, VolumeError::BadGeometry, "offset {:#x}", offset);
+    }
+    // root cluster of FAT32 out of range
+    let mut boot = ram_image(FatType::FAT32, 70000, 1)[0..512].to_vec();
+    boot[0x2C..0x30].copy_from_slice(&1u32.to_le_bytes());
+    assert_eq!(Volume::try_new(BootSectorDisk { boot }).err().unwrap().1, VolumeError::BadGeometry);
+}
@@ -0,0 +1,238 @@
+//! Device calls of file reads AND writes: runs of consecutive sectors, FAT[1] written once till flush,
+//! no FAT search with bitmap
+
+mod common;
+
+use std::cell::RefCell;
+use std::convert::TryInto;
+use common::{
+    ram_image,
+    pattern,
+    FS_INFO_SECTOR,
+};
+use common::raw::Raw;
+use common::recorder::{
+    Call,
+    Calls,
+    Recorder,
+};
+use fat32::bpb::FatType;
+use fat32::volume::Volume;
+use fat32::ram_disk::RamDisk;
+use fat32::file::WriteType;
+
+/// Each round appends 3 clusters AND a bit more to FRAG.BIN, then a cluster to BLOCK.BIN,
+/// so the chain of FRAG.BIN is broken by the cluster of BLOCK.BIN
+fn fragment(volume: &Volume<Recorder>, rounds: usize) -> Vec<u8> {
+    let cluster_size = 1024;
+    let mut root = volume.root_dir();
+    root.create_file("frag.bin").unwrap();
+    root.create_file("block.bin").unwrap();
+
+    let mut data = vec![];
+    for round in 0..rounds {
+        let chunk = pattern(cluster_size * 3 + 100, round);
+        root.open_file("frag.bin").unwrap().write(&chunk, WriteType::Append).unwrap();
+        data.extend_from_slice(&chunk);
+        root.open_file("block.bin").unwrap().write(&pattern(cluster_size, 9), WriteType::Append).unwrap();
+    }
+    data
+}
+
+#[test]
+fn blocks_per_transfer() {
+    // cluster is 2 sectors, transfers of 3 sectors end in the middle of cluster
+    let mut image = ram_image(FatType::FAT16, 40000, 2);
+    let calls = RefCell::new(vec![]);
+    let volume = Volume::new(Recorder { disk: RamDisk::new(&mut image), calls: &calls })
+        .with_blocks_per_transfer(3);
+    let data = fragment(&volume, 4);
+    let writes = calls.take();
+    assert!(writes.iter().all(|call| call.blocks <= 3));
+    assert!(writes.iter().any(|call| call.write && call.blocks == 3));
+
+    let file = volume.root_dir().open_file("frag.bin").unwrap();
+    let mut buf = vec![0; data.len()];
+    assert_eq!(file.read(&mut buf), Ok(data.len()));
+    assert_eq!(buf, data);
+
+    // FAT sectors are single reads, data goes in runs no longer than 3
+    let reads = calls.take();
+    assert!(reads.iter().all(|call| !call.write && call.blocks <= 3));
+    assert!(reads.iter().any(|call| call.blocks == 3));
+    assert!(reads.iter().map(|call| call.blocks).sum::<usize>() >= data.len().div_ceil(512));
+
+    // one block per transfer still reads the same data
+    drop(file);
+    let volume = volume.with_blocks_per_transfer(1);
+    let mut buf = vec![0; data.len()];
+    assert_eq!(volume.root_dir().open_file("frag.bin").unwrap().read(&mut buf), Ok(data.len()));
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn extents_and_offset_sector() {
+    let mut image = ram_image(FatType::FAT16, 40000, 2);
+    let calls = RefCell::new(vec![]);
+    let length = {
+        let volume = Volume::new(Recorder { disk: RamDisk::new(&mut image), calls: &calls });
+        fragment(&volume, 4).len()
+    };
+
+    // every sector of the file, from the chain in FAT
+    let raw = Raw::new(&image);
+    let first = raw.cluster_of(&image, raw.root_cluster, b"FRAG    BIN");
+    let sectors: Vec<u32> = raw.chain(&image, first).iter()
+        .flat_map(|&cluster| {
+            let sector = (raw.cluster_offset(cluster) / 512) as u32;
+            sector..sector + 2
+        })
+        .take(length.div_ceil(512))
+        .collect();
+    let mut runs: Vec<(u32, usize)> = vec![];
+    for &sector in sectors.iter() {
+        match runs.last_mut() {
+            Some((start, count)) if *start + *count as u32 == sector => *count += 1,
+            _ => runs.push((sector, 1)),
+        }
+    }
+    assert!(runs.len() > 1);
+
+    let volume = Volume::new(RamDisk::new(&mut image));
+    let file = volume.root_dir().open_file("frag.bin").unwrap();
+    let mut extents = file.extents();
+    assert_eq!(extents.by_ref().collect::<Vec<_>>(), runs);
+    assert!(!extents.is_corrupted());
+
+    // the last sector is part of a sector, offsets in it are there too
+    for offset in (0..length).step_by(500).chain([length - 1]) {
+        assert_eq!(file.offset_sector(offset), Some(sectors[offset / 512]), "offset {}", offset);
+    }
+    assert_eq!(file.offset_sector(length), None);
+}
+
+#[test]
+fn clean_bit_cleared_once() {
+    let mut image = ram_image(FatType::FAT16, 40000, 8);
+    let calls = RefCell::new(vec![]);
+    let volume = Volume::new(Recorder { disk: RamDisk::new(&mut image), calls: &calls });
+    let mut root = volume.root_dir();
+    // the chain of log.bin is past the first FAT sector, which holds FAT[1]
+    root.create_file_with_size("pad.bin", 300 * 4096, false).unwrap();
+    root.create_file("log.bin").unwrap();
+    volume.flush().unwrap();
+
+    // every append into the cluster is the same, only the first one goes to the first FAT sector
+    let mut file = root.open_file("log.bin").unwrap();
+    let first_fat_sector = |calls: &[Call]| calls.iter().filter(|call| call.sector == 1).count();
+    for seed in 0..4 {
+        calls.take();
+        file.write(&pattern(512, seed), WriteType::Append).unwrap();
+        let calls = calls.take();
+        assert_eq!(first_fat_sector(&calls) != 0, seed == 0, "append {}", seed);
+    }
+
+    // flush sets the bit, the next append clears it again
+    volume.flush().unwrap();
+    calls.take();
+    file.write(&pattern(512, 4), WriteType::Append).unwrap();
+    assert_ne!(first_fat_sector(&calls.take()), 0);
+}
+
+/// Create, write, append AND delete files, then reserve a file past every used cluster.
+/// Return the sectors which the reserve read without writing them
+fn allocations(volume: &Volume<Recorder>, calls: &Calls, cluster_size: usize) -> Vec<usize> {
+    let mut root = volume.root_dir();
+    root.create_file_with_size("pad.bin", 3000 * cluster_size, false).unwrap();
+    root.create_file("log.bin").unwrap();
+    let mut file = root.open_file("log.bin").unwrap();
+    file.write(&pattern(5 * cluster_size + 100, 1), WriteType::OverWritten).unwrap();
+    file.write(&pattern(3 * cluster_size, 2), WriteType::Append).unwrap();
+    file.write(&pattern(2 * cluster_size, 3), WriteType::OverWritten).unwrap();
+    root.create_dir("logs").unwrap();
+    root.delete_file("log.bin").unwrap();
+
+    calls.take();
+    root.create_file_with_size("data.bin", 10 * cluster_size, true).unwrap();
+    let calls = calls.take();
+    let mut read_only: Vec<usize> = calls.iter()
+        .filter(|read| !read.write && !calls.iter().any(|write| write.write && write.sector == read.sector))
+        .map(|read| read.sector)
+        .collect();
+    read_only.dedup();
+    volume.flush().unwrap();
+    read_only
+}
+
+#[test]
+fn bitmap_allocator() {
+    for (fat_type, total_sector, spc) in [(FatType::FAT16, 40000, 2), (FatType::FAT32, 70000, 1)] {
+        let empty = ram_image(fat_type, total_sector, spc);
+        let cluster_size = spc as usize * 512;
+        // FAT copies AND the root dir of FAT16 end at the first data sector,
+        // the first FAT sector is left out, it holds the chain of the FAT32 root dir which is followed
+        let reserved = u16::from_le_bytes([empty[0x0E], empty[0x0F]]) as usize;
+        let fat_sectors = reserved + 1..Raw::new(&empty).cluster_offset(2) / 512;
+
+        let calls = RefCell::new(vec![]);
+        let mut scanned = empty.clone();
+        let read_only = allocations(&Volume::new(Recorder { disk: RamDisk::new(&mut scanned), calls: &calls }),
+                                    &calls, cluster_size);
+        // FAT is searched for free clusters past pad.bin
+        assert!(read_only.iter().filter(|sector| fat_sectors.contains(sector)).count() > 1, "{:?}", fat_type);
+
+        let mut image = empty.clone();
+        let mut bitmap = vec![0; Volume::new(RamDisk::new(&mut image)).bitmap_size()];
+        {
+            let volume = Volume::new(Recorder { disk: RamDisk::new(&mut image), calls: &calls })
+                .with_bitmap(&mut bitmap).ok().unwrap();
+            let read_only = allocations(&volume, &calls, cluster_size);
+            // FAT sectors are read only to update entries in them, bitmap finds the free clusters
+            assert_eq!(read_only.iter().filter(|sector| fat_sectors.contains(sector)).count(), 0, "{:?}", fat_type);
+        }
+
+        // FSInfo counts are known only with bitmap, everything else is the same
+        if fat_type == FatType::FAT32 {
+            let counts = FS_INFO_SECTOR as usize * 512 + 488;
+            assert_eq!(scanned[counts..counts + 8], [0xFF; 8]);
+            let free_count = u32::from_le_bytes(image[counts..counts + 4].try_into().unwrap());
+            assert_eq!(free_count as usize, Raw::new(&image).free_clusters(&image));
+            image[counts..counts + 8].copy_from_slice(&[0xFF; 8]);
+        }
+        assert!(image == scanned, "{:?}", fat_type);
+    }
+}
+
+#[test]
+fn contiguous_runs() {
+    let mut image = ram_image(FatType::FAT16, 40000, 8);
+    let first_data = Raw::new(&image).cluster_offset(2) / 512;
+    let data_calls = |calls: &Calls, write: bool| -> Vec<usize> {
+        calls.take().iter()
+            .filter(|call| call.write == write && call.sector >= first_data)
+            .map(|call| call.blocks)
+            .collect()
+    };
+    let calls = RefCell::new(vec![]);
+    let volume = Volume::new(Recorder { disk: RamDisk::new(&mut image), calls: &calls })
+        .with_blocks_per_transfer(3);
+    let mut root = volume.root_dir();
+    root.create_file("cont.bin").unwrap();
+    let mut file = root.open_file("cont.bin").unwrap();
+    file.write(&pattern(100, 1), WriteType::OverWritten).unwrap();
+
+    // the sector with the end of file is filled, the rest of its cluster AND the next ones go in runs
+    let sectors = 20;
+    let data = [pattern(100, 1), pattern(sectors * 512 - 100, 2)].concat();
+    calls.take();
+    file.write(&data[100..], WriteType::Append).unwrap();
+    assert_eq!(data_calls(&calls, true), [vec![1], vec![3; 6], vec![1]].concat());
+    assert_eq!(file.extents().count(), 1);
+
+    // contiguous file of N sectors is read in ceil(N / limit) calls
+    let mut buf = vec![0; data.len()];
+    calls.take();
+    assert_eq!(file.read(&mut buf), Ok(data.len()));
+    assert_eq!(buf, data);
+    assert_eq!(data_calls(&calls, false).len(), sectors.div_ceil(3));
+}